@@ -0,0 +1,184 @@
+use anyhow::{anyhow, ensure};
+use rust_decimal::Decimal;
+use serde::de::{self, Deserializer, Visitor};
+use std::fmt;
+use std::str::FromStr;
+
+/// Number of decimal places amounts are kept with.
+pub(crate) const PRECISION: u32 = 4;
+
+/// Amounts scaled to integers are in units of 10^-`PRECISION`.
+pub(crate) const SCALE: i128 = 10_i128.pow(PRECISION);
+
+/// Parses decimal amount, fraction `a/b` is rounded to the `PRECISION` if allowed.
+/// Every input format parses amounts with it, negative ones are rejected by the txs.
+pub(crate) fn parse(s: &str, allow_fractions: bool) -> anyhow::Result<Decimal> {
+    match s.split_once('/') {
+        Some((numerator, denominator)) => {
+            ensure!(allow_fractions, "Fraction amounts are not allowed {}", s);
+            let numerator = parse_decimal(numerator.trim())?;
+            let denominator = parse_decimal(denominator.trim())?;
+            ensure!(!denominator.is_zero(), "Zero denominator in amount {}", s);
+            ensure_representable(s, (numerator / denominator).round_dp(PRECISION))
+        }
+        None => parse_decimal(s),
+    }
+}
+
+/// Amount as an integer number of the smallest units, see [`SCALE`].
+pub(crate) fn to_scaled(amount: Decimal) -> i128 {
+    let mut amount = amount.round_dp(PRECISION);
+    amount.rescale(PRECISION);
+    amount.mantissa()
+}
+
+fn parse_decimal(s: &str) -> anyhow::Result<Decimal> {
+    let amount = Decimal::from_str(s)
+        .or_else(|_| Decimal::from_scientific(s))
+        .map_err(|_| anyhow!("Invalid amount {}", s))?;
+    ensure_representable(s, amount)
+}
+
+/// Rejects nonzero amounts which would round to zero at the `PRECISION`,
+/// so they are not mistaken for a literal zero.
+fn ensure_representable(s: &str, amount: Decimal) -> anyhow::Result<Decimal> {
+    ensure!(
+        amount.is_zero() || !amount.round_dp(PRECISION).is_zero(),
+        "Amount {} below minimum representable unit (precision {})",
+        s,
+        PRECISION
+    );
+    Ok(amount)
+}
+
+/// Deserializes amount given as a number or a string, see [`parse`], fractions are not allowed.
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+    deserializer.deserialize_any(AmountVisitor)
+}
+
+struct AmountVisitor;
+
+impl<'de> Visitor<'de> for AmountVisitor {
+    type Value = Decimal;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a decimal amount")
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Decimal, E> {
+        Ok(v.into())
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Decimal, E> {
+        Ok(v.into())
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Decimal, E> {
+        parse_decimal(&v.to_string()).map_err(E::custom)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Decimal, E> {
+        parse(v, false).map_err(E::custom)
+    }
+}
+
+/// Deserializes optional amount given as a number or a string into its text, to be parsed
+/// with [`parse`] later, empty or null one is `None`.
+pub(crate) fn deserialize_raw<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    deserializer.deserialize_any(RawAmountVisitor)
+}
+
+struct RawAmountVisitor;
+
+impl<'de> Visitor<'de> for RawAmountVisitor {
+    type Value = Option<String>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an optional decimal amount")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Option<String>, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Option<String>, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Option<String>, D::Error> {
+        deserialize_raw(deserializer)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Option<String>, E> {
+        Ok(Some(v.to_string()))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Option<String>, E> {
+        Ok(Some(v.to_string()))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Option<String>, E> {
+        Ok(Some(v.to_string()))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Option<String>, E> {
+        let v = v.trim();
+        Ok((!v.is_empty()).then(|| v.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_plain_amounts() -> anyhow::Result<()> {
+        assert_eq!(parse("1.5", false)?, Decimal::from_str("1.5")?);
+        assert_eq!(parse("0", false)?, Decimal::ZERO);
+        assert_eq!(parse("abc", false).unwrap_err().to_string(), "Invalid amount abc");
+        Ok(())
+    }
+
+    #[test]
+    fn should_parse_fractions_when_allowed() -> anyhow::Result<()> {
+        assert_eq!(parse("1/3", true)?, Decimal::from_str("0.3333")?);
+        assert_eq!(parse("1/2", true)?, Decimal::from_str("0.5000")?);
+        assert_eq!(parse("1.5", true)?, Decimal::from_str("1.5")?);
+        assert_eq!(
+            parse("1/0", true).unwrap_err().to_string(),
+            "Zero denominator in amount 1/0"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_parse_fractions_by_default() -> anyhow::Result<()> {
+        assert_eq!(
+            parse("1/3", false).unwrap_err().to_string(),
+            "Fraction amounts are not allowed 1/3"
+        );
+        assert_eq!(parse("1.5", false)?, Decimal::from_str("1.5")?);
+        Ok(())
+    }
+
+    #[test]
+    fn should_scale_amounts() -> anyhow::Result<()> {
+        assert_eq!(to_scaled(Decimal::from_str("1.5")?), 15000);
+        assert_eq!(to_scaled(Decimal::from_str("0.00016")?), 2);
+        assert_eq!(to_scaled(Decimal::from_str("-2")?), -20000);
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_parse_sub_precision_amounts() -> anyhow::Result<()> {
+        assert_eq!(
+            parse("0.00001", false).unwrap_err().to_string(),
+            "Amount 0.00001 below minimum representable unit (precision 4)"
+        );
+        assert_eq!(parse("0", false)?, Decimal::ZERO);
+        assert_eq!(parse("0.0001", false)?, Decimal::from_str("0.0001")?);
+        Ok(())
+    }
+}
@@ -0,0 +1,393 @@
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::num::NonZeroU64;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+use crate::client::DrainAction;
+use crate::engine::{Config, Engine};
+use crate::gen::{Generator, Weights};
+use crate::input::{self, InputFormat};
+use crate::output::{self, LockedFormat, OutputFormat, SortKey, ViewOptions};
+use crate::settle::SettlePolicy;
+use crate::tx::{self, Policy};
+#[cfg(feature = "watch")]
+use crate::watch;
+
+#[derive(Debug, StructOpt)]
+/// Parses CSV input files (or stdin) with txs, processes them and outputs the state of clients as CSV
+struct Opt {
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+    /// Input files processed in order as one input, stdin if not given
+    #[structopt(parse(from_os_str))]
+    input_csv: Vec<PathBuf>,
+    /// Write the output to this file instead of stdout
+    #[structopt(short, long = "output", parse(from_os_str), value_name = "PATH")]
+    output_csv: Option<PathBuf>,
+    /// Log rows which are skipped, with the reason, to stderr
+    #[structopt(short, long)]
+    verbose: bool,
+    /// Skip input files which cannot be read instead of aborting
+    #[structopt(long)]
+    skip_bad_inputs: bool,
+    /// Process the input files again whenever they change
+    #[cfg(feature = "watch")]
+    #[structopt(long)]
+    watch: bool,
+    /// Print the expected input format as JSON and exit
+    #[structopt(long)]
+    dump_schema: bool,
+    /// Print counts of unknown tx types in the input CSV as `type,count` and exit
+    #[structopt(long)]
+    validate_types: bool,
+    /// Report progress of reading the input to stderr, as percentage of the file
+    /// or number of lines read from stdin
+    #[structopt(long)]
+    progress: bool,
+    /// Process the input without writing the output nor reports, print counts of valid
+    /// and invalid rows to stderr and fail if there are any invalid ones
+    #[structopt(long)]
+    check: bool,
+    /// Format of the input file, `auto` detects it from the content
+    #[structopt(long, default_value = "csv", possible_values = InputFormat::VARIANTS)]
+    input_format: InputFormat,
+    /// Process only txs with id greater or equal to this one
+    #[structopt(long)]
+    min_tx_id: Option<u32>,
+    /// Process only txs with id less or equal to this one
+    #[structopt(long)]
+    max_tx_id: Option<u32>,
+    /// Fail if the change of clients' totals does not match the processed txs
+    #[structopt(long)]
+    assert_balanced: bool,
+    /// Accept amounts given as fractions (e.g. `1/3`), rounded to 4 decimal places
+    #[structopt(long)]
+    allow_fractions: bool,
+    /// Abort as soon as a tx leaves client's funds negative or not adding up
+    #[structopt(long, conflicts_with = "workers")]
+    strict: bool,
+    /// Abort on the first row which cannot be parsed or applied, instead of skipping it
+    #[structopt(long, conflicts_with = "workers")]
+    fail_fast: bool,
+    /// Abort if a dispute, resolve or chargeback precedes its deposit
+    #[structopt(long)]
+    strict_order: bool,
+    /// Representation of the `locked` column
+    #[structopt(long, default_value = "bool", possible_values = LockedFormat::VARIANTS)]
+    locked_format: LockedFormat,
+    /// Output amounts as integers in units of 0.0001, after a comment line noting the scale
+    #[structopt(long)]
+    raw_amounts: bool,
+    /// Decimal places amounts are rounded to in the output, up to 10
+    #[structopt(long, parse(try_from_str = output::parse_precision))]
+    precision: Option<u32>,
+    /// Add `lock_reason` column telling whether the account is locked by chargeback or admin freeze
+    #[structopt(long)]
+    with_lock_reason: bool,
+    /// Add `deposit_count` and `withdrawal_count` columns with numbers of applied ones
+    /// and `currency` column
+    #[structopt(long)]
+    extended: bool,
+    /// Add `worst_case_total` column with the total left if all open disputes were charged back
+    #[structopt(long)]
+    risk: bool,
+    /// Resolve or chargeback deposits still being disputed at the end of processing
+    #[structopt(long, possible_values = DrainAction::VARIANTS)]
+    drain_disputes: Option<DrainAction>,
+    /// Process CSV input by this many threads, each owning a shard of clients
+    #[structopt(
+        long,
+        value_name = "N",
+        conflicts_with_all = &[
+            "fast-parse",
+            "batch-commit",
+            "holds-report",
+            "ledger",
+            "pending-disputes",
+            "checkpoint",
+            "audit",
+        ]
+    )]
+    workers: Option<usize>,
+    /// Keep up to N disputes, resolves and chargebacks of txs not seen yet,
+    /// processing them once the tx arrives
+    #[structopt(long, value_name = "N", conflicts_with = "strict-order")]
+    pending_disputes: Option<usize>,
+    /// Delimiter of the CSV input, a single ASCII character
+    #[structopt(long, parse(try_from_str = input::parse_delimiter), value_name = "CHAR")]
+    delimiter: Option<u8>,
+    /// Read and write tab-separated values instead of comma-separated ones
+    #[structopt(long, conflicts_with_all = &["delimiter", "pretty"])]
+    tsv: bool,
+    /// Parse CSV input with a hand-rolled parser instead of serde
+    #[structopt(long)]
+    fast_parse: bool,
+    /// Sort clients in the output by the key, ties are broken by client id, `none` is the fastest
+    #[structopt(long, default_value = "id", possible_values = SortKey::VARIANTS)]
+    sort: SortKey,
+    /// Reject withdrawals leaving available funds greater than zero, but less than the threshold
+    #[structopt(long, value_name = "THRESHOLD")]
+    no_dust: Option<Decimal>,
+    /// Reject deposits and withdrawals of amount greater than this one
+    #[structopt(long, value_name = "AMOUNT")]
+    max_amount: Option<Decimal>,
+    /// Accept administrative freeze and unfreeze txs
+    #[structopt(long)]
+    allow_admin_freeze: bool,
+    /// Create nonexistent account on withdrawal, so it is in the output even if withdrawal fails
+    #[structopt(long)]
+    create_on_withdrawal: bool,
+    /// Accept administrative delta txs adjusting the funds by `available_delta`, `held_delta`
+    /// and `total_delta` columns
+    #[structopt(long)]
+    allow_delta: bool,
+    /// Accept adjustment txs correcting available and total funds by signed `amount`
+    #[structopt(long)]
+    allow_adjustment: bool,
+    /// Accept disputes of withdrawals, holding the withdrawn funds until resolved or charged back
+    #[structopt(long)]
+    allow_withdrawal_disputes: bool,
+    /// Ignore resolves and chargebacks of deposits which are not disputed instead of skipping them
+    /// as errors
+    #[structopt(long)]
+    ignore_resolve_chargeback_without_dispute: bool,
+    /// Dispute with an amount refers to the most recent undisputed deposit of exactly that amount,
+    /// not to the deposit with its tx id
+    #[structopt(long)]
+    dispute_by_amount: bool,
+    /// Dispute with an amount disputes only that part of the deposit
+    #[structopt(long, conflicts_with = "dispute-by-amount")]
+    allow_partial_disputes: bool,
+    /// Treat deposit of negative amount as withdrawal and vice versa, instead of rejecting it
+    #[structopt(long)]
+    signed_amounts: bool,
+    /// Abort if SHA-256 of the input file (hex encoded) differs
+    #[structopt(long, value_name = "SHA256")]
+    checksum_input: Option<String>,
+    /// Write final state of each client as JSON lines, versioned by the number of applied txs
+    #[structopt(long, parse(from_os_str), value_name = "PATH")]
+    cdc: Option<PathBuf>,
+    /// Skip input lines longer than this number of bytes, counting them as invalid rows,
+    /// lines are not limited by default
+    #[structopt(long, value_name = "BYTES")]
+    max_line_length: Option<usize>,
+    /// Retry opening or reading the input this many times on IO errors, waiting longer every time
+    #[structopt(long, default_value = "0", value_name = "N")]
+    io_retries: u32,
+    /// Flush the output after every N clients instead of only at the end
+    #[structopt(long, value_name = "N")]
+    flush_every: Option<usize>,
+    /// Fail if any client's total ends below this amount
+    #[structopt(long, value_name = "AMOUNT")]
+    balance_floor: Option<Decimal>,
+    /// Fail if any client's total ends above this amount
+    #[structopt(long, value_name = "AMOUNT")]
+    balance_ceiling: Option<Decimal>,
+    /// Settle funds held by deposits still being disputed at the end of processing
+    #[structopt(long, possible_values = SettlePolicy::VARIANTS)]
+    settle: Option<SettlePolicy>,
+    /// CSV with `client` and `policy` columns overriding the settle policy per client
+    #[structopt(long, parse(from_os_str), value_name = "PATH", requires = "settle")]
+    settle_overrides: Option<PathBuf>,
+    /// CSV log of applied txs, rerun on the same input applies them again and skips their rows,
+    /// new ones are appended
+    #[structopt(long, parse(from_os_str), value_name = "PATH", conflicts_with = "pending-disputes")]
+    checkpoint: Option<PathBuf>,
+    /// Output CSV of a previous run, its clients are loaded before processing the input
+    #[structopt(long, parse(from_os_str), value_name = "PATH")]
+    seed: Option<PathBuf>,
+    /// Write CSV with total funds held by all clients over time
+    #[structopt(long, parse(from_os_str), value_name = "PATH")]
+    holds_report: Option<PathBuf>,
+    /// Number of rows between records of the holds report
+    #[structopt(long, default_value = "1000", value_name = "ROWS")]
+    holds_interval: NonZeroU64,
+    /// Write CSV with every deposit, its state and number of disputes
+    #[structopt(long, parse(from_os_str), value_name = "PATH")]
+    deposits_report: Option<PathBuf>,
+    /// Write CSV with every tx still being disputed, by client
+    #[structopt(long, parse(from_os_str), value_name = "PATH")]
+    disputes_report: Option<PathBuf>,
+    /// Write CSV with every operation applied to balances and the balances after it
+    #[structopt(long, parse(from_os_str), value_name = "PATH")]
+    ledger: Option<PathBuf>,
+    /// Write CSV with the balances before and after every applied tx
+    /// and the reason of every skipped one
+    #[structopt(long, parse(from_os_str), value_name = "PATH", conflicts_with = "batch-commit")]
+    audit: Option<PathBuf>,
+    /// Apply rows in batches of N, rolling back a batch which leaves any client's funds
+    /// negative or not adding up
+    #[structopt(long, value_name = "N")]
+    batch_commit: Option<NonZeroU64>,
+    /// Print counts of rows by tx type, skipped rows and locked accounts to stderr
+    #[structopt(long)]
+    stats: bool,
+    /// Write JSON with counts of rows, errors, clients and locked ones, and volumes of txs
+    #[structopt(long, parse(from_os_str), value_name = "PATH")]
+    summary_json: Option<PathBuf>,
+    /// CSV with `source` and `target` columns, source accounts are merged into target ones
+    #[structopt(long, parse(from_os_str), value_name = "PATH")]
+    merge_accounts: Option<PathBuf>,
+    /// Format of the output
+    #[structopt(long, default_value = "csv", possible_values = OutputFormat::VARIANTS)]
+    format: OutputFormat,
+    /// Output clients as JSON object with client ids as sorted keys
+    #[structopt(long)]
+    json_map: bool,
+    /// Output clients as a table with aligned columns, for humans
+    #[structopt(long, conflicts_with = "json-map")]
+    pretty: bool,
+    /// Leave out unlocked clients whose available, held and total funds are all zero
+    #[structopt(long)]
+    hide_empty: bool,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Generates CSV file with random txs for benchmarks to the stdout
+    Gen {
+        #[structopt(long, default_value = "1000000")]
+        rows: usize,
+        #[structopt(long, default_value = "1000")]
+        clients: u16,
+        /// Relative frequencies of deposits, withdrawals, disputes, resolves and chargebacks
+        #[structopt(long, default_value = "1,1,1,1,1")]
+        weights: Weights,
+        /// Make disputes, resolves and chargebacks reference already generated deposits
+        #[structopt(long)]
+        valid_disputes: bool,
+    },
+}
+
+impl Opt {
+    fn config(&self) -> Config {
+        Config {
+            input_format: self.input_format,
+            min_tx_id: self.min_tx_id,
+            max_tx_id: self.max_tx_id,
+            assert_balanced: self.assert_balanced,
+            allow_fractions: self.allow_fractions,
+            strict_order: self.strict_order,
+            view: ViewOptions {
+                locked_format: self.locked_format,
+                raw_amounts: self.raw_amounts,
+                lock_reason: self.with_lock_reason,
+                precision: self.precision,
+                counts: self.extended,
+                currency: self.extended,
+                risk: self.risk,
+                ..Default::default()
+            },
+            drain_disputes: self.drain_disputes,
+            fast_parse: self.fast_parse,
+            sort: self.sort,
+            policy: Policy {
+                dust_threshold: self.no_dust,
+                max_amount: self.max_amount,
+                allow_admin_freeze: self.allow_admin_freeze,
+                create_on_withdrawal: self.create_on_withdrawal,
+                allow_delta: self.allow_delta,
+                allow_withdrawal_disputes: self.allow_withdrawal_disputes,
+                allow_adjustment: self.allow_adjustment,
+                ignore_undisputed: self.ignore_resolve_chargeback_without_dispute,
+                dispute_by_amount: self.dispute_by_amount,
+                signed_amounts: self.signed_amounts,
+                allow_partial_disputes: self.allow_partial_disputes,
+            },
+            checksum: self.checksum_input.clone(),
+            cdc: self.cdc.clone(),
+            max_line_length: self.max_line_length,
+            settle: self.settle,
+            settle_overrides: self.settle_overrides.clone(),
+            holds_report: self.holds_report.clone(),
+            holds_interval: Some(self.holds_interval),
+            deposits_report: self.deposits_report.clone(),
+            disputes_report: self.disputes_report.clone(),
+            ledger: self.ledger.clone(),
+            batch_commit: self.batch_commit,
+            summary_json: self.summary_json.clone(),
+            output: self.output_csv.clone(),
+            skip_bad_inputs: self.skip_bad_inputs,
+            merge_accounts: self.merge_accounts.clone(),
+            json_map: self.json_map,
+            output_format: self.format,
+            pretty: self.pretty,
+            hide_empty: self.hide_empty,
+            io_retries: self.io_retries,
+            flush_every: self.flush_every,
+            balance_floor: self.balance_floor,
+            balance_ceiling: self.balance_ceiling,
+            verbose: self.verbose,
+            workers: self.workers.unwrap_or(1),
+            pending_disputes: self.pending_disputes,
+            strict: self.strict,
+            checkpoint: self.checkpoint.clone(),
+            stats: self.stats,
+            seed: self.seed.clone(),
+            audit: self.audit.clone(),
+            progress: self.progress,
+            delimiter: self.delimiter,
+            tsv: self.tsv,
+            fail_fast: self.fail_fast,
+        }
+    }
+}
+
+/// Runs the command line tool with the arguments of the process.
+pub fn main() -> anyhow::Result<()> {
+    let opt = Opt::from_args();
+    if let Some(Command::Gen {
+        rows,
+        clients,
+        weights,
+        valid_disputes,
+    }) = opt.cmd
+    {
+        let generator = Generator {
+            rows,
+            clients,
+            weights,
+            valid_disputes,
+        };
+        return generator.generate(std::io::stdout());
+    }
+    if opt.dump_schema {
+        return tx::dump_schema(std::io::stdout());
+    }
+    if opt.validate_types {
+        let config = opt.config();
+        let (policy, delimiter) = (&config.policy, config.delimiter());
+        let mut unknown = BTreeMap::new();
+        if opt.input_csv.is_empty() {
+            unknown = tx::unknown_types(std::io::stdin().lock(), policy, delimiter)?;
+        }
+        for path in &opt.input_csv {
+            for (name, count) in tx::unknown_types(File::open(path)?, policy, delimiter)? {
+                *unknown.entry(name).or_insert(0) += count;
+            }
+        }
+        let mut wtr = csv::Writer::from_writer(std::io::stdout());
+        wtr.write_record(["type", "count"])?;
+        for (name, count) in unknown {
+            wtr.serialize((name, count))?;
+        }
+        return Ok(wtr.flush()?);
+    }
+    if opt.check {
+        return Engine::new(opt.config())?.check(opt.input_csv.clone());
+    }
+    #[cfg(feature = "watch")]
+    if opt.watch {
+        let mut engine = Engine::new(opt.config())?;
+        return watch::watch(&opt.input_csv, || {
+            engine.reset();
+            if let Err(e) = engine.run(opt.input_csv.clone()) {
+                eprintln!("Error: {}", e);
+            }
+            Ok(true)
+        });
+    }
+    Engine::new(opt.config())?.run(opt.input_csv.clone())
+}
@@ -1,59 +1,331 @@
-use anyhow::{anyhow, bail, ensure};
+use anyhow::bail;
 use rust_decimal::{Decimal, prelude::Zero};
-use serde::ser::{Serialize, SerializeStruct, Serializer};
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 
-#[derive(Debug, PartialEq)]
-enum DepositState {
+use crate::amount;
+use crate::output::{LockedFormat, ViewOptions};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DepositState {
+    #[default]
     Ok,
     Dispute,
+    /// Final, the disputed funds are moved back and the tx cannot be disputed again.
+    ChargedBack,
+}
+
+/// What to do with deposits still being disputed at the end of processing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum DrainAction {
+    Resolve,
+    Chargeback,
 }
 
-impl Default for DepositState {
-    fn default() -> Self {
-        DepositState::Ok
+impl DrainAction {
+    pub(crate) const VARIANTS: &'static [&'static str] = &["resolve", "chargeback"];
+}
+
+impl FromStr for DrainAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "resolve" => DrainAction::Resolve,
+            "chargeback" => DrainAction::Chargeback,
+            _ => bail!("Unknown drain action {}", s),
+        })
     }
 }
 
-#[derive(Debug)]
-struct Deposit {
+/// Which way the funds of a stored tx went, so disputing it moves them the opposite way.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TxKind {
+    /// Funds came into the account, a chargeback takes them away and locks the account.
+    Deposit,
+    /// Funds went out of the account, a chargeback returns them to the client.
+    Withdrawal,
+}
+
+/// Deposit, or withdrawal when allowed to be disputed, stored so it might be disputed.
+#[derive(Clone, Debug)]
+struct StoredTx {
+    kind: TxKind,
     amount: Decimal,
+    /// Part of the amount currently held by the dispute.
+    disputed: Decimal,
     state: DepositState,
+    /// Number of times the deposit has been disputed.
+    dispute_count: u32,
+    /// Number of deposits and withdrawals made by the client before this one.
+    seq: u64,
 }
 
-impl Deposit {
-    fn ensure_state(&self, state: DepositState) -> anyhow::Result<()> {
+impl StoredTx {
+    fn ensure_state(&self, state: DepositState) -> Result<(), ClientError> {
         if self.state != state {
-            bail!("Deposit in state {:?} != {:?}", self.state, state)
+            return Err(ClientError::BadState { state: self.state, expected: state });
         }
         Ok(())
     }
 }
 
-#[derive(Debug, Default)]
-pub(crate) struct Client {
-    client_id: u16,
-    pub(crate) available: Decimal,
-    pub(crate) held: Decimal,
-    pub(crate) total: Decimal,
-    locked: bool,
-    // storing only deposits, as only them may be disputed
-    deposits: HashMap<u32, Deposit>,
+#[derive(Clone, Debug, Default)]
+pub struct Client {
+    pub client_id: u16,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+    /// Administrative lock, unlike `locked` it might be lifted.
+    pub frozen: bool,
+    /// Number of txs applied to the client.
+    pub(crate) version: u64,
+    /// Number of deposits and withdrawals applied to the client.
+    pub(crate) deposit_count: u64,
+    pub(crate) withdrawal_count: u64,
+    /// ISO 4217 code of the funds, fixed by the first tx having it.
+    pub(crate) currency: Option<String>,
+    // storing only deposits, and withdrawals when allowed to be disputed, as only them may be
+    txs: HashMap<u32, StoredTx>,
+    /// Most recent tx if it is a withdrawal not stored in `txs`, so it might be undone.
+    last_withdrawal: Option<(u32, Decimal)>,
+}
+
+/// Why an account is locked.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockReason {
+    Chargeback,
+    AdminFreeze,
+}
+
+/// Why a tx cannot be applied to the client, so callers might tell the reasons apart.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientError {
+    NegativeAmount(Decimal),
+    Locked { client: u16, reason: LockReason },
+    AlreadyFrozen { client: u16 },
+    NotFrozen { client: u16 },
+    Overflow { client: u16 },
+    /// Not enough of the `funds`, i.e. `available`, `held` or `in total`.
+    InsufficientFunds { client: u16, funds: &'static str, needed: Decimal, present: Decimal },
+    Dust { client: u16, amount: Decimal, left: Decimal },
+    NotFound { tx: u32 },
+    AmountNotFound { amount: Decimal },
+    BadState { state: DepositState, expected: DepositState },
+    ResolvingMoreThanDisputed { tx: u32, amount: Decimal, disputed: Decimal },
+    DisputingMoreThanRemains { tx: u32, amount: Decimal, remaining: Decimal },
+    NothingToUndo { client: u16 },
+    /// Held funds would go negative, which the deposit states should prevent.
+    HeldUnderflow { client: u16 },
+    CurrencyMismatch { client: u16, currency: String, expected: String },
+    UndoingDisputed { tx: u32 },
+    MergeConflict { source: u16, target: u16, tx: u32 },
+    BrokenDelta { client: u16, available: Decimal, held: Decimal, total: Decimal },
+    NegativeFunds { client: u16, available: Decimal, held: Decimal },
+    InconsistentFunds { client: u16, available: Decimal, held: Decimal, total: Decimal },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::NegativeAmount(amount) => write!(f, "Negative amount {}", amount),
+            ClientError::Locked { client, reason: LockReason::Chargeback } => {
+                write!(f, "Account {} is locked", client)
+            }
+            ClientError::Locked { client, reason: LockReason::AdminFreeze } => {
+                write!(f, "Account {} is frozen", client)
+            }
+            ClientError::AlreadyFrozen { client } => {
+                write!(f, "Account {} is already frozen", client)
+            }
+            ClientError::NotFrozen { client } => write!(f, "Account {} is not frozen", client),
+            ClientError::Overflow { client } => {
+                write!(f, "Balance overflow for account {}", client)
+            }
+            ClientError::InsufficientFunds { client, funds, needed, present } => write!(
+                f,
+                "Account {}: Not enough funds {}: {} > {}",
+                client, funds, needed, present
+            ),
+            ClientError::Dust { client, amount, left } => write!(
+                f,
+                "Account {}: Withdrawal of {} would leave dust balance {}",
+                client, amount, left
+            ),
+            ClientError::NotFound { tx } => write!(f, "Deposit not found {}", tx),
+            ClientError::AmountNotFound { amount } => {
+                write!(f, "Undisputed deposit of {} not found", amount)
+            }
+            ClientError::BadState { state, expected } => {
+                write!(f, "Deposit in state {:?} != {:?}", state, expected)
+            }
+            ClientError::ResolvingMoreThanDisputed { tx, amount, disputed } => write!(
+                f,
+                "Deposit {}: Resolving more than disputed: {} > {}",
+                tx, amount, disputed
+            ),
+            ClientError::DisputingMoreThanRemains { tx, amount, remaining } => write!(
+                f,
+                "Deposit {}: Disputing more than remains: {} > {}",
+                tx, amount, remaining
+            ),
+            ClientError::CurrencyMismatch { client, currency, expected } => {
+                write!(f, "Account {}: Currency {} != {}", client, currency, expected)
+            }
+            ClientError::HeldUnderflow { client } => {
+                write!(f, "Held underflow for account {}", client)
+            }
+            ClientError::NothingToUndo { client } => {
+                write!(f, "Account {}: Nothing to undo", client)
+            }
+            ClientError::UndoingDisputed { tx } => {
+                write!(f, "Deposit {}: Cannot undo once disputed", tx)
+            }
+            ClientError::MergeConflict { source, target, tx } => write!(
+                f,
+                "Cannot merge account {} into {}: both have deposit {}",
+                source, target, tx
+            ),
+            ClientError::BrokenDelta { client, available, held, total } => write!(
+                f,
+                "Account {}: Delta would break available + held == total: {} + {} != {}",
+                client, available, held, total
+            ),
+            ClientError::NegativeFunds { client, available, held } => write!(
+                f,
+                "Account {}: Negative funds: available {}, held {}",
+                client, available, held
+            ),
+            ClientError::InconsistentFunds { client, available, held, total } => write!(
+                f,
+                "Account {}: Inconsistent funds: available {} + held {} != total {}",
+                client, available, held, total
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// Client's balances at some point, e.g. after applying a tx.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientSnapshot {
+    pub client_id: u16,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    /// Locked by chargeback or frozen.
+    pub locked: bool,
+}
+
+/// Row of the deposits report.
+#[derive(Debug, Serialize)]
+pub(crate) struct DepositRow {
+    client: u16,
+    tx: u32,
+    amount: Decimal,
+    disputed: Decimal,
+    state: DepositState,
+    dispute_count: u32,
 }
 
 impl Serialize for Client {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let mut state = serializer.serialize_struct("Client", 5)?;
-        state.serialize_field("client", &self.client_id)?;
-        state.serialize_field("available", &self.available.round_dp(4))?;
-        state.serialize_field("held", &self.held.round_dp(4))?;
-        state.serialize_field("total", &self.total.round_dp(4))?;
-        state.serialize_field("locked", &self.locked)?;
+        self.view(ViewOptions::default()).serialize(serializer)
+    }
+}
+
+/// Client's state as written to the output.
+pub(crate) struct ClientView<'a> {
+    client: &'a Client,
+    options: ViewOptions,
+}
+
+impl Serialize for ClientView<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let c = self.client;
+        let precision = self.options.precision.unwrap_or(amount::PRECISION);
+        let len = 4
+            + usize::from(!self.options.without_id)
+            + usize::from(self.options.version)
+            + usize::from(self.options.lock_reason)
+            + 2 * usize::from(self.options.counts)
+            + usize::from(self.options.currency)
+            + usize::from(self.options.risk);
+        let mut state = serializer.serialize_struct("Client", len)?;
+        if !self.options.without_id {
+            state.serialize_field("client", &c.client_id)?;
+        }
+        for (name, amount) in [("available", c.available), ("held", c.held), ("total", c.total)] {
+            match self.options.raw_amounts {
+                true => state.serialize_field(name, &amount::to_scaled(amount))?,
+                false => state.serialize_field(name, &amount.round_dp(precision))?,
+            }
+        }
+        let locked = c.locked_or_frozen();
+        match self.options.locked_format {
+            LockedFormat::Bool => state.serialize_field("locked", &locked)?,
+            LockedFormat::Int => state.serialize_field("locked", &u8::from(locked))?,
+            LockedFormat::Yn => state.serialize_field("locked", if locked { "Y" } else { "N" })?,
+        }
+        if self.options.version {
+            state.serialize_field("version", &c.version)?;
+        }
+        if self.options.lock_reason {
+            state.serialize_field("lock_reason", &c.lock_reason())?;
+        }
+        if self.options.counts {
+            state.serialize_field("deposit_count", &c.deposit_count)?;
+            state.serialize_field("withdrawal_count", &c.withdrawal_count)?;
+        }
+        if self.options.currency {
+            state.serialize_field("currency", &c.currency)?;
+        }
+        if self.options.risk {
+            let worst_case = c.available_after_pending_disputes().round_dp(precision);
+            state.serialize_field("worst_case_total", &worst_case)?;
+        }
         state.end()
     }
 }
 
 impl Client {
+    pub(crate) fn view(&self, options: ViewOptions) -> ClientView<'_> {
+        ClientView {
+            client: self,
+            options,
+        }
+    }
+
+    pub fn snapshot(&self) -> ClientSnapshot {
+        ClientSnapshot {
+            client_id: self.client_id,
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked_or_frozen(),
+        }
+    }
+
+    /// Locked by chargeback or frozen, as shown in the output.
+    pub(crate) fn locked_or_frozen(&self) -> bool {
+        self.locked || self.frozen
+    }
+
+    /// Unlocked with no funds at all, so there is nothing to show about it.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.available.is_zero()
+            && self.held.is_zero()
+            && self.total.is_zero()
+            && !self.locked_or_frozen()
+    }
+
     pub(crate) fn create(client_id: u16) -> Self {
         Client {
             client_id,
@@ -61,124 +333,603 @@ impl Client {
             held: Decimal::zero(),
             total: Decimal::zero(),
             locked: false,
-            deposits: Default::default(),
+            frozen: false,
+            version: 0,
+            deposit_count: 0,
+            withdrawal_count: 0,
+            currency: None,
+            txs: Default::default(),
+            last_withdrawal: None,
         }
     }
 
+    /// Client with pre-set funds, e.g. seeded from a prior snapshot.
+    /// Fails unless the funds are non-negative and `total == available + held`.
+    pub(crate) fn with_balance(
+        client_id: u16,
+        available: Decimal,
+        held: Decimal,
+        total: Decimal,
+    ) -> Result<Self, ClientError> {
+        let client = Client {
+            available,
+            held,
+            total,
+            ..Client::create(client_id)
+        };
+        client.ensure_consistent()?;
+        Ok(client)
+    }
+
     /// A deposit increases the available and total funds.
     /// Only positive amounts are accepted.
-    /// Deposit is not allowed on locked account.
-    pub(crate) fn deposit(&mut self, tx_id: u32, amount: Decimal) -> anyhow::Result<()> {
-        ensure!(amount >= 0.into(), "Negative amount {}", amount);
+    /// Deposit is not allowed on locked account, nor when the funds would overflow.
+    pub(crate) fn deposit(&mut self, tx_id: u32, amount: Decimal) -> Result<(), ClientError> {
+        if amount < 0.into() {
+            return Err(ClientError::NegativeAmount(amount));
+        }
         self.ensure_unlocked()?;
-        self.deposits.insert(
+        let (Some(available), Some(total)) =
+            (self.available.checked_add(amount), self.total.checked_add(amount))
+        else {
+            return Err(ClientError::Overflow { client: self.client_id });
+        };
+        self.txs.insert(
             // tx ids are unique
             tx_id,
-            Deposit {
-                amount: amount.clone(),
+            StoredTx {
+                kind: TxKind::Deposit,
+                amount,
+                disputed: Decimal::zero(),
                 state: DepositState::Ok,
+                dispute_count: 0,
+                seq: self.deposit_count + self.withdrawal_count,
             },
         );
 
-        self.available += &amount;
-        self.total += &amount;
+        self.available = available;
+        self.total = total;
+        self.deposit_count += 1;
+        self.last_withdrawal = None;
         Ok(())
     }
 
     /// A withdraw decreases the available and total funds.
     /// Only positive amounts are accepted.
     /// It is not allowed to withdraw from locked account or exceeding available funds.
-    pub(crate) fn withdraw(&mut self, amount: Decimal) -> anyhow::Result<()> {
-        ensure!(amount >= 0.into(), "Negative amount {}", amount);
+    /// The withdrawal is not stored, so it cannot be disputed.
+    pub(crate) fn withdraw(&mut self, tx_id: u32, amount: Decimal) -> Result<(), ClientError> {
+        self.withdraw_as(tx_id, amount, false)
+    }
+
+    /// A withdraw which is stored, so it might be disputed later.
+    pub(crate) fn withdraw_disputable(
+        &mut self,
+        tx_id: u32,
+        amount: Decimal,
+    ) -> Result<(), ClientError> {
+        self.withdraw_as(tx_id, amount, true)
+    }
+
+    fn withdraw_as(
+        &mut self,
+        tx_id: u32,
+        amount: Decimal,
+        disputable: bool,
+    ) -> Result<(), ClientError> {
+        if amount < 0.into() {
+            return Err(ClientError::NegativeAmount(amount));
+        }
         self.ensure_unlocked()?;
-        ensure!(
-            self.available >= amount,
-            "Account {}: Not enough funds available: {} > {}",
-            self.client_id,
-            amount,
-            self.available,
-        );
+        if self.available < amount {
+            return Err(ClientError::InsufficientFunds {
+                client: self.client_id,
+                funds: "available",
+                needed: amount,
+                present: self.available,
+            });
+        }
         self.available -= &amount;
         self.total -= &amount;
+        self.last_withdrawal = None;
+        if disputable {
+            self.txs.insert(
+                tx_id,
+                StoredTx {
+                    kind: TxKind::Withdrawal,
+                    amount,
+                    disputed: Decimal::zero(),
+                    state: DepositState::Ok,
+                    dispute_count: 0,
+                    seq: self.deposit_count + self.withdrawal_count,
+                },
+            );
+        } else {
+            self.last_withdrawal = Some((tx_id, amount));
+        }
+        self.withdrawal_count += 1;
         Ok(())
     }
 
+    /// A withdraw which is not allowed to leave available funds
+    /// greater than zero, but less than the threshold.
+    pub(crate) fn withdraw_leaving_no_dust(
+        &mut self,
+        tx_id: u32,
+        amount: Decimal,
+        threshold: Decimal,
+        disputable: bool,
+    ) -> Result<(), ClientError> {
+        let left = self.available - amount;
+        if left > Decimal::zero() && left < threshold {
+            return Err(ClientError::Dust { client: self.client_id, amount, left });
+        }
+        self.withdraw_as(tx_id, amount, disputable)
+    }
+
     /// A dispute decreases available funds by the amount disputed, increases held funds,
     /// total funds remain the same.
     /// It is only allowed to dispute Deposits which are not being disputed nor been chargedback.
     /// It is possible to dispute already resolved Deposits.
+    /// Partially resolved Deposit might be disputed again, only for the resolved part.
     /// It is not allowed to dispute when there is not enough available funds.
+    /// A dispute of Withdrawal increases held and total funds instead, as the funds might be
+    /// returned, available funds remain the same.
     /// Dispute is not allowed for locked account.
-    pub(crate) fn dispute(&mut self, tx_id: &u32) -> anyhow::Result<()> {
+    /// Returns the amount moved to held funds.
+    pub(crate) fn dispute(&mut self, tx_id: &u32) -> Result<Decimal, ClientError> {
+        let remaining = self
+            .txs
+            .get(tx_id)
+            .map_or(Decimal::zero(), |d| d.amount - d.disputed);
+        self.dispute_part(tx_id, remaining)
+    }
+
+    /// A dispute of only a part of the deposit, e.g. contested by a regulator.
+    /// Deposit might be disputed in parts until all of it is disputed.
+    /// It is not allowed to dispute more than remains undisputed.
+    pub(crate) fn dispute_part(
+        &mut self,
+        tx_id: &u32,
+        amount: Decimal,
+    ) -> Result<Decimal, ClientError> {
+        if amount < 0.into() {
+            return Err(ClientError::NegativeAmount(amount));
+        }
         self.ensure_unlocked()?;
         let deposit = self
-            .deposits
+            .txs
             .get_mut(tx_id)
-            .ok_or(anyhow!("Deposit not found {}", tx_id))?;
-        deposit.ensure_state(DepositState::Ok)?;
-        ensure!(
-            self.available >= deposit.amount,
-            "Account {}: Not enough funds available: {} > {}",
-            self.client_id,
-            deposit.amount,
-            self.available,
-        );
-        self.available -= &deposit.amount;
-        self.held += &deposit.amount;
+            .ok_or(ClientError::NotFound { tx: *tx_id })?;
+        if deposit.state == DepositState::ChargedBack || deposit.disputed == deposit.amount {
+            deposit.ensure_state(DepositState::Ok)?;
+        }
+        if amount > deposit.amount - deposit.disputed {
+            return Err(ClientError::DisputingMoreThanRemains {
+                tx: *tx_id,
+                amount,
+                remaining: deposit.amount - deposit.disputed,
+            });
+        }
+        match deposit.kind {
+            TxKind::Deposit => {
+                if self.available < amount {
+                    return Err(ClientError::InsufficientFunds {
+                        client: self.client_id,
+                        funds: "available",
+                        needed: amount,
+                        present: self.available,
+                    });
+                }
+                self.available -= &amount;
+            }
+            TxKind::Withdrawal => self.total += &amount,
+        }
+        self.held += &amount;
+        deposit.disputed += &amount;
         deposit.state = DepositState::Dispute;
-        Ok(())
+        deposit.dispute_count += 1;
+        Ok(amount)
+    }
+
+    /// A dispute of the most recent deposit of exactly the amount which is not disputed yet,
+    /// for callers not knowing the tx id.
+    /// Returns the amount moved to held funds.
+    pub(crate) fn dispute_by_amount(&mut self, amount: Decimal) -> Result<Decimal, ClientError> {
+        let tx_id = self
+            .txs
+            .iter()
+            .filter(|(_, d)| d.kind == TxKind::Deposit && d.state == DepositState::Ok)
+            .filter(|(_, d)| d.amount == amount)
+            .max_by_key(|(tx_id, d)| (d.seq, **tx_id))
+            .map(|(tx_id, _)| *tx_id)
+            .ok_or(ClientError::AmountNotFound { amount })?;
+        self.dispute(&tx_id)
     }
 
     /// A resolve decreases held funds by the amount no longer disputed, increases available funds,
     /// total funds remain the same.
     /// It is only allowed to resolve Deposits which are being disputed, but not been charged back.
+    /// A resolve of Withdrawal decreases held and total funds instead, as the withdrawal stands.
     /// Resolve is not allowed even locked account.
-    pub(crate) fn resolve(&mut self, tx_id: &u32) -> anyhow::Result<()> {
+    /// Returns the amount released from held funds.
+    pub(crate) fn resolve(&mut self, tx_id: &u32) -> Result<Decimal, ClientError> {
+        let disputed = self.txs.get(tx_id).map_or(Decimal::zero(), |d| d.disputed);
+        self.resolve_part(tx_id, disputed)
+    }
+
+    /// A resolve of only a part of the disputed amount, the rest stays held.
+    /// Deposit is no longer disputed once all of it is resolved.
+    /// It is not allowed to resolve more than is disputed.
+    pub(crate) fn resolve_part(
+        &mut self,
+        tx_id: &u32,
+        amount: Decimal,
+    ) -> Result<Decimal, ClientError> {
         self.ensure_unlocked()?;
         let deposit = self
-            .deposits
+            .txs
             .get_mut(tx_id)
-            .ok_or(anyhow!("Deposit not found {}", tx_id))?;
+            .ok_or(ClientError::NotFound { tx: *tx_id })?;
         deposit.ensure_state(DepositState::Dispute)?;
-        self.available += &deposit.amount;
-        // no need to check held funds, bc we had checked state already
-        self.held -= &deposit.amount;
-        deposit.state = DepositState::Ok;
-        Ok(())
+        if amount > deposit.disputed {
+            return Err(ClientError::ResolvingMoreThanDisputed {
+                tx: *tx_id,
+                amount,
+                disputed: deposit.disputed,
+            });
+        }
+        // disputed amount is held, so it fails only if the state is broken, e.g. by a bug
+        if self.held < amount {
+            return Err(ClientError::HeldUnderflow { client: self.client_id });
+        }
+        match deposit.kind {
+            TxKind::Deposit => self.available += &amount,
+            TxKind::Withdrawal => self.total -= &amount,
+        }
+        self.held -= &amount;
+        deposit.disputed -= &amount;
+        if deposit.disputed.is_zero() {
+            deposit.state = DepositState::Ok;
+        }
+        Ok(amount)
     }
 
-    /// A chargeback decreases clients held funds and total funds by the amount previously disputed.
+    /// A chargeback decreases clients held funds and total funds by the amount still disputed,
+    /// i.e. without the part already resolved.
     /// A chargeback makes client's account locked / frozen.
     /// It is only allowed to chargeback Deposits which are being disputed.
-    /// It is not allowed to chargeback when there are not enough total funds available.
+    /// A chargeback of Withdrawal returns the held funds to available ones instead,
+    /// total funds remain the same and the account is not locked, as the client is not at fault.
+    /// It is not allowed to chargeback when there are not enough held or total funds.
     /// Chargeback is not allowed for locked account.
-    pub(crate) fn chargeback(&mut self, tx_id: &u32) -> anyhow::Result<()> {
+    /// Returns the amount charged back, lost by the client or returned for Withdrawal.
+    pub(crate) fn chargeback(&mut self, tx_id: &u32) -> Result<Decimal, ClientError> {
         self.ensure_unlocked()?;
+        let (kind, amount) = self.charge_back(tx_id)?;
+        self.locked |= kind == TxKind::Deposit;
+        Ok(amount)
+    }
+
+    fn charge_back(&mut self, tx_id: &u32) -> Result<(TxKind, Decimal), ClientError> {
         let deposit = self
-            .deposits
+            .txs
             .get_mut(tx_id)
-            .ok_or(anyhow!("Deposit not found {}", tx_id))?;
+            .ok_or(ClientError::NotFound { tx: *tx_id })?;
         deposit.ensure_state(DepositState::Dispute)?;
-        ensure!(
-            self.total >= deposit.amount,
-            "Account {}: Not enough funds in total: {} > {}",
-            self.client_id,
-            deposit.amount,
-            self.total,
+        if self.held < deposit.disputed {
+            return Err(ClientError::InsufficientFunds {
+                client: self.client_id,
+                funds: "held",
+                needed: deposit.disputed,
+                present: self.held,
+            });
+        }
+        match deposit.kind {
+            TxKind::Deposit => {
+                if self.total < deposit.disputed {
+                    return Err(ClientError::InsufficientFunds {
+                        client: self.client_id,
+                        funds: "in total",
+                        needed: deposit.disputed,
+                        present: self.total,
+                    });
+                }
+                self.total -= &deposit.disputed;
+            }
+            TxKind::Withdrawal => self.available += &deposit.disputed,
+        }
+        self.held -= &deposit.disputed;
+        deposit.state = DepositState::ChargedBack;
+        Ok((deposit.kind, std::mem::take(&mut deposit.disputed)))
+    }
+
+    /// Reverses the most recent deposit or withdrawal, e.g. entered by mistake,
+    /// as if it had never been applied, so its tx id is forgotten too.
+    /// It is not allowed once the tx has been disputed, even if resolved since then,
+    /// nor for locked account, e.g. after a chargeback, which cannot be undone.
+    /// Undoing a deposit is not allowed when its amount is not available anymore.
+    /// Once a withdrawal which cannot be disputed is undone, the tx before it is not known.
+    /// Returns the tx id undone.
+    pub fn undo_last(&mut self) -> Result<u32, ClientError> {
+        self.ensure_unlocked()?;
+        if let Some((tx_id, amount)) = self.last_withdrawal.take() {
+            self.available += amount;
+            self.total += amount;
+            self.withdrawal_count -= 1;
+            self.version += 1;
+            return Ok(tx_id);
+        }
+        let count = self.deposit_count + self.withdrawal_count;
+        let (tx_id, last) = self
+            .txs
+            .iter()
+            .max_by_key(|(tx_id, d)| (d.seq, **tx_id))
+            .filter(|(_, d)| d.seq + 1 == count)
+            .ok_or(ClientError::NothingToUndo { client: self.client_id })?;
+        let tx_id = *tx_id;
+        if last.dispute_count > 0 {
+            return Err(ClientError::UndoingDisputed { tx: tx_id });
+        }
+        match last.kind {
+            TxKind::Deposit => {
+                if self.available < last.amount {
+                    return Err(ClientError::InsufficientFunds {
+                        client: self.client_id,
+                        funds: "available",
+                        needed: last.amount,
+                        present: self.available,
+                    });
+                }
+                self.available -= last.amount;
+                self.total -= last.amount;
+                self.deposit_count -= 1;
+            }
+            TxKind::Withdrawal => {
+                self.available += last.amount;
+                self.total += last.amount;
+                self.withdrawal_count -= 1;
+            }
+        }
+        self.txs.remove(&tx_id);
+        self.version += 1;
+        Ok(tx_id)
+    }
+
+    /// Applies the action to every deposit still being disputed.
+    /// On chargeback the account gets locked after all of them are charged back,
+    /// unless only withdrawals were.
+    /// Draining is not allowed for locked account.
+    /// Returns the sum of drained amounts.
+    pub(crate) fn drain_disputes(&mut self, action: DrainAction) -> Result<Decimal, ClientError> {
+        self.ensure_unlocked()?;
+        let tx_ids = self.disputed_tx_ids();
+        let mut drained = Decimal::zero();
+        let mut lock = false;
+        for tx_id in &tx_ids {
+            drained += match action {
+                DrainAction::Resolve => self.resolve(tx_id)?,
+                DrainAction::Chargeback => {
+                    let (kind, amount) = self.charge_back(tx_id)?;
+                    lock |= kind == TxKind::Deposit;
+                    amount
+                }
+            };
+        }
+        self.locked |= lock;
+        Ok(drained)
+    }
+
+    /// Whether the deposit is being disputed, `None` if there is no such deposit.
+    pub(crate) fn is_disputed(&self, tx_id: &u32) -> Option<bool> {
+        self.txs.get(tx_id).map(|d| d.state == DepositState::Dispute)
+    }
+
+    /// Whether the tx is a withdrawal, so disputing it moves funds the opposite direction.
+    pub(crate) fn is_withdrawal(&self, tx_id: &u32) -> bool {
+        self.txs.get(tx_id).is_some_and(|d| d.kind == TxKind::Withdrawal)
+    }
+
+    /// Total funds left if all deposits being disputed were charged back, for risk display.
+    /// Available funds are not affected by chargebacks, only the disputed funds held are lost.
+    pub(crate) fn available_after_pending_disputes(&self) -> Decimal {
+        let disputed: Decimal = self
+            .txs
+            .values()
+            .filter(|d| d.kind == TxKind::Deposit && d.state == DepositState::Dispute)
+            .map(|d| d.disputed)
+            .sum();
+        self.total - disputed
+    }
+
+    /// Funds held by withdrawals being disputed.
+    pub(crate) fn disputed_withdrawals(&self) -> Decimal {
+        self.txs.values().filter(|d| d.kind == TxKind::Withdrawal).map(|d| d.disputed).sum()
+    }
+
+    /// Ids of deposits being disputed, sorted.
+    pub(crate) fn disputed_tx_ids(&self) -> Vec<u32> {
+        let mut tx_ids: Vec<u32> = self
+            .txs
+            .iter()
+            .filter(|(_, d)| d.state == DepositState::Dispute)
+            .map(|(tx_id, _)| *tx_id)
+            .collect();
+        tx_ids.sort_unstable();
+        tx_ids
+    }
+
+    /// Deposits of the client for the deposits report, sorted by tx id.
+    pub(crate) fn deposit_rows(&self) -> Vec<DepositRow> {
+        let mut rows: Vec<DepositRow> = self
+            .txs
+            .iter()
+            .filter(|(_, d)| d.kind == TxKind::Deposit)
+            .map(|(tx_id, d)| DepositRow {
+                client: self.client_id,
+                tx: *tx_id,
+                amount: d.amount,
+                disputed: d.disputed,
+                state: d.state,
+                dispute_count: d.dispute_count,
+            })
+            .collect();
+        rows.sort_unstable_by_key(|r| r.tx);
+        rows
+    }
+
+    /// Merges other client into this one, e.g. after finding out both belong to the same person.
+    /// Balances are added up, deposits are moved, so they still might be disputed,
+    /// account is locked / frozen if any of them was.
+    /// Txs of the other client are taken as made after the ones of this client.
+    /// It is not allowed to merge clients having deposits with the same tx id.
+    pub(crate) fn merge_from(&mut self, other: Client) -> Result<(), ClientError> {
+        if let Some(tx_id) = other.txs.keys().find(|id| self.txs.contains_key(id)) {
+            return Err(ClientError::MergeConflict {
+                source: other.client_id,
+                target: self.client_id,
+                tx: *tx_id,
+            });
+        }
+        if let Some(currency) = &other.currency {
+            self.ensure_currency(currency)?;
+        }
+        self.currency = self.currency.take().or(other.currency);
+        self.available += other.available;
+        self.held += other.held;
+        self.total += other.total;
+        self.locked |= other.locked;
+        self.frozen |= other.frozen;
+        self.version += other.version;
+        let count = self.deposit_count + self.withdrawal_count;
+        if other.deposit_count + other.withdrawal_count > 0 {
+            self.last_withdrawal = other.last_withdrawal;
+        }
+        self.deposit_count += other.deposit_count;
+        self.withdrawal_count += other.withdrawal_count;
+        self.txs.extend(
+            other
+                .txs
+                .into_iter()
+                .map(|(tx_id, d)| (tx_id, StoredTx { seq: d.seq + count, ..d })),
         );
-        self.total -= &deposit.amount;
-        self.held -= &deposit.amount;
-        self.locked = true;
         Ok(())
     }
 
-    fn ensure_unlocked(&self) -> anyhow::Result<()> {
-        ensure!(!self.locked, "Account {} is locked", self.client_id);
+    /// A delta adjusts the funds directly, e.g. to correct them after reconciliation.
+    /// It is not allowed to break `available + held == total`, nor to leave funds negative.
+    /// Delta is allowed even for locked account.
+    pub(crate) fn apply_delta(
+        &mut self,
+        available: Decimal,
+        held: Decimal,
+        total: Decimal,
+    ) -> Result<(), ClientError> {
+        let available = self.available + available;
+        let held = self.held + held;
+        let total = self.total + total;
+        if available + held != total {
+            return Err(ClientError::BrokenDelta { client: self.client_id, available, held, total });
+        }
+        Client { available, held, total, ..Client::create(self.client_id) }.ensure_consistent()?;
+        self.available = available;
+        self.held = held;
+        self.total = total;
+        Ok(())
+    }
+
+    /// An adjustment corrects available and total funds by the signed amount, e.g. after
+    /// a manual review. It cannot be disputed, as it is not a deposit.
+    /// Adjustment is allowed even for locked account, as a correction of its funds.
+    /// It is not allowed to leave available funds negative.
+    pub(crate) fn adjust(&mut self, amount: Decimal) -> Result<(), ClientError> {
+        let available = self.available + amount;
+        if available < Decimal::zero() {
+            return Err(ClientError::InsufficientFunds {
+                client: self.client_id,
+                funds: "available",
+                needed: -amount,
+                present: self.available,
+            });
+        }
+        self.available = available;
+        self.total += amount;
+        Ok(())
+    }
+
+    /// A freeze locks the account administratively, until it is unfrozen.
+    /// Freezing account locked by chargeback is allowed, but not already frozen one.
+    pub(crate) fn freeze(&mut self) -> Result<(), ClientError> {
+        if self.frozen {
+            return Err(ClientError::AlreadyFrozen { client: self.client_id });
+        }
+        self.frozen = true;
+        Ok(())
+    }
+
+    /// An unfreeze lifts the administrative lock, it does not unlock account locked by chargeback.
+    /// Unfreezing not frozen account is not allowed.
+    pub(crate) fn unfreeze(&mut self) -> Result<(), ClientError> {
+        if !self.frozen {
+            return Err(ClientError::NotFrozen { client: self.client_id });
+        }
+        self.frozen = false;
         Ok(())
     }
+
+    /// Checks that funds are not negative and add up, i.e. `available + held == total`.
+    pub(crate) fn ensure_consistent(&self) -> Result<(), ClientError> {
+        if self.available < Decimal::zero() || self.held < Decimal::zero() {
+            return Err(ClientError::NegativeFunds {
+                client: self.client_id,
+                available: self.available,
+                held: self.held,
+            });
+        }
+        if self.available + self.held != self.total {
+            return Err(ClientError::InconsistentFunds {
+                client: self.client_id,
+                available: self.available,
+                held: self.held,
+                total: self.total,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks that the funds are in the currency, or in no currency yet.
+    pub(crate) fn ensure_currency(&self, currency: &str) -> Result<(), ClientError> {
+        match &self.currency {
+            Some(expected) if expected != currency => Err(ClientError::CurrencyMismatch {
+                client: self.client_id,
+                currency: currency.to_string(),
+                expected: expected.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Chargeback lock takes precedence, as it cannot be lifted.
+    pub(crate) fn lock_reason(&self) -> Option<LockReason> {
+        if self.locked {
+            Some(LockReason::Chargeback)
+        } else if self.frozen {
+            Some(LockReason::AdminFreeze)
+        } else {
+            None
+        }
+    }
+
+    fn ensure_unlocked(&self) -> Result<(), ClientError> {
+        match self.lock_reason() {
+            Some(reason) => Err(ClientError::Locked { client: self.client_id, reason }),
+            None => Ok(()),
+        }
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::approx_constant)]
 mod tests {
     use super::*;
     use rust_decimal::{Decimal, prelude::FromPrimitive};
@@ -193,7 +944,7 @@ mod tests {
             assert_eq!(self.available, Decimal::from_f64(available).unwrap());
             assert_eq!(self.held, Decimal::from_f64(held).unwrap());
             assert_eq!(self.total, Decimal::from_f64(total).unwrap());
-            assert_ne!(self.locked, true);
+            assert!(!self.locked);
         }
 
         fn is_locked(&self, available: f64, held: f64, total: f64) {
@@ -204,6 +955,158 @@ mod tests {
         }
     }
 
+    fn to_csv(c: &Client, locked_format: LockedFormat) -> String {
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        wtr.serialize(c.view(ViewOptions {
+            locked_format,
+            ..Default::default()
+        }))
+        .unwrap();
+        String::from_utf8(wtr.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn should_serialize_locked_in_chosen_format() -> anyhow::Result<()> {
+        let mut c = Client::create(7);
+        c.deposit(1, 2.into())?;
+        assert_eq!(
+            to_csv(&c, LockedFormat::Bool),
+            "client,available,held,total,locked\n7,2.0,0.0,2.0,false\n"
+        );
+        assert_eq!(to_csv(&c, LockedFormat::Int), "client,available,held,total,locked\n7,2.0,0.0,2.0,0\n");
+        assert_eq!(to_csv(&c, LockedFormat::Yn), "client,available,held,total,locked\n7,2.0,0.0,2.0,N\n");
+        c.dispute(&1)?;
+        c.chargeback(&1)?;
+        assert_eq!(
+            to_csv(&c, LockedFormat::Bool),
+            "client,available,held,total,locked\n7,0.0,0.0,0.0,true\n"
+        );
+        assert_eq!(to_csv(&c, LockedFormat::Int), "client,available,held,total,locked\n7,0.0,0.0,0.0,1\n");
+        assert_eq!(to_csv(&c, LockedFormat::Yn), "client,available,held,total,locked\n7,0.0,0.0,0.0,Y\n");
+        Ok(())
+    }
+
+    #[test]
+    fn should_count_deposits_and_withdrawals() -> anyhow::Result<()> {
+        let mut c = Client::create(7);
+        c.deposit(1, 2.into())?;
+        c.deposit(2, 3.into())?;
+        c.withdraw(3, 1.into())?;
+        c.withdraw(4, 10.into()).unwrap_err();
+        c.deposit(5, 1.into())?;
+        assert_eq!((c.deposit_count, c.withdrawal_count), (3, 1));
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        wtr.serialize(c.view(ViewOptions {
+            counts: true,
+            ..Default::default()
+        }))?;
+        assert_eq!(
+            String::from_utf8(wtr.into_inner()?)?,
+            "client,available,held,total,locked,deposit_count,withdrawal_count\n\
+             7,5.0,0.0,5.0,false,3,1\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_undo_last_deposit_or_withdrawal() -> anyhow::Result<()> {
+        let mut c = Client::create(0);
+        c.deposit(1, 5.into())?;
+        c.deposit(2, 3.into())?;
+        c.withdraw(3, 2.into())?;
+        assert_eq!(c.undo_last()?, 3);
+        c.is(8., 0., 8.);
+        c.withdraw_disputable(4, 1.into())?;
+        assert_eq!(c.undo_last()?, 4);
+        assert_eq!(c.dispute(&4), Err(ClientError::NotFound { tx: 4 }));
+        assert_eq!(c.undo_last()?, 2);
+        c.is(5., 0., 5.);
+        assert_eq!((c.deposit_count, c.withdrawal_count), (1, 0));
+        // tx id is forgotten, so it might be disputed no more
+        assert_eq!(c.dispute(&2), Err(ClientError::NotFound { tx: 2 }));
+        c.dispute(&1)?;
+        c.resolve(&1)?;
+        assert_eq!(c.undo_last(), Err(ClientError::UndoingDisputed { tx: 1 }));
+        c.is(5., 0., 5.);
+        c.deposit(4, 3.into())?;
+        c.adjust((-6).into())?;
+        assert_eq!(
+            c.undo_last().unwrap_err().to_string(),
+            "Account 0: Not enough funds available: 3 > 2"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_undo_on_locked_account() -> anyhow::Result<()> {
+        let mut c = Client::create(0);
+        assert_eq!(c.undo_last(), Err(ClientError::NothingToUndo { client: 0 }));
+        c.deposit(1, 5.into())?;
+        c.deposit(2, 3.into())?;
+        c.dispute(&1)?;
+        c.chargeback(&1)?;
+        assert_eq!(
+            c.undo_last().unwrap_err().to_string(),
+            "Account 0 is locked"
+        );
+        c.is_locked(3., 0., 3.);
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_undo_before_withdrawal_not_stored() -> anyhow::Result<()> {
+        let mut c = Client::create(0);
+        c.deposit(1, 5.into())?;
+        c.withdraw(2, 1.into())?;
+        c.withdraw(3, 1.into())?;
+        assert_eq!(c.undo_last()?, 3);
+        // withdrawal 2 is not stored, so deposit 1 is not the most recent tx
+        assert_eq!(c.undo_last(), Err(ClientError::NothingToUndo { client: 0 }));
+        c.is(4., 0., 4.);
+        assert!(c.txs.values().all(|d| d.kind == TxKind::Deposit));
+        Ok(())
+    }
+
+    #[test]
+    fn should_tell_total_left_after_open_disputes_charged_back() -> anyhow::Result<()> {
+        let mut c = Client::create(0);
+        c.deposit(1, 5.into())?;
+        c.deposit(2, 3.into())?;
+        c.deposit(3, 1.into())?;
+        c.withdraw(4, 2.into())?;
+        assert_eq!(c.available_after_pending_disputes(), 7.into());
+        c.dispute(&1)?;
+        c.dispute(&3)?;
+        c.is(1., 6., 7.);
+        assert_eq!(c.available_after_pending_disputes(), 1.into());
+        c.resolve(&3)?;
+        assert_eq!(c.available_after_pending_disputes(), 2.into());
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        wtr.serialize(c.view(ViewOptions {
+            risk: true,
+            ..Default::default()
+        }))?;
+        assert_eq!(
+            String::from_utf8(wtr.into_inner()?)?,
+            "client,available,held,total,locked,worst_case_total\n0,2.0,5.0,7.0,false,2.0\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_count_charged_back_deposit_as_risk() -> anyhow::Result<()> {
+        let mut c = Client::create(0);
+        c.deposit(1, 5.into())?;
+        c.deposit(2, 3.into())?;
+        c.dispute(&2)?;
+        assert_eq!(c.available_after_pending_disputes(), 5.into());
+        c.chargeback(&2)?;
+        c.is_locked(5., 0., 5.);
+        assert_eq!(c.available_after_pending_disputes(), 5.into());
+        assert_eq!(c.is_disputed(&2), Some(false));
+        Ok(())
+    }
+
     #[test]
     fn should_properly_handle_deposit() -> anyhow::Result<()> {
         let mut c = Client::default();
@@ -217,7 +1120,7 @@ mod tests {
         let mut c = Client::default();
         c.deposit(1, Decimal::from_f64(494475.4876).unwrap())?;
         c.is(494475.4876, 0., 494475.4876);
-        c.withdraw(Decimal::from_f64(96658.5182).unwrap())?;
+        c.withdraw(10, Decimal::from_f64(96658.5182).unwrap())?;
         c.is(494475.4876 - 96658.5182, 0., 494475.4876 - 96658.5182);
         Ok(())
     }
@@ -234,6 +1137,71 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn should_return_amounts_of_dispute_resolve_and_chargeback() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        c.deposit(1, Decimal::from_f64(3.14).unwrap())?;
+        c.deposit(2, 2.into())?;
+        assert_eq!(c.dispute(&1)?, Decimal::from_f64(3.14).unwrap());
+        assert_eq!(c.resolve(&1)?, Decimal::from_f64(3.14).unwrap());
+        assert_eq!(c.dispute(&2)?, 2.into());
+        assert_eq!(c.chargeback(&2)?, 2.into());
+        c.is_locked(3.14, 0., 3.14);
+        Ok(())
+    }
+
+    #[test]
+    fn should_drain_disputes_by_resolving() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        c.deposit(1, 1.into())?;
+        c.deposit(2, 2.into())?;
+        c.deposit(3, 4.into())?;
+        c.dispute(&1)?;
+        c.dispute(&3)?;
+        c.is(2., 5., 7.);
+        assert_eq!(c.disputed_tx_ids(), vec![1, 3]);
+        assert_eq!(c.drain_disputes(DrainAction::Resolve)?, 5.into());
+        c.is(7., 0., 7.);
+        assert!(c.disputed_tx_ids().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn should_list_only_open_disputes() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        for tx_id in 1..=4 {
+            c.deposit(tx_id, 1.into())?;
+        }
+        c.dispute(&4)?;
+        c.dispute(&2)?;
+        c.dispute(&3)?;
+        c.resolve(&3)?;
+        assert_eq!(c.disputed_tx_ids(), [2, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_drain_disputes_by_charging_back() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        c.deposit(1, 1.into())?;
+        c.deposit(2, 2.into())?;
+        c.deposit(3, 4.into())?;
+        c.dispute(&1)?;
+        c.dispute(&3)?;
+        assert_eq!(c.drain_disputes(DrainAction::Chargeback)?, 5.into());
+        c.is_locked(2., 0., 2.);
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_lock_when_nothing_to_drain() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        c.deposit(1, 1.into())?;
+        assert_eq!(c.drain_disputes(DrainAction::Chargeback)?, 0.into());
+        c.is(1., 0., 1.);
+        Ok(())
+    }
+
     #[test]
     fn should_not_allow_disputes_for_unknown_id() -> anyhow::Result<()> {
         let mut c = Client::default();
@@ -264,6 +1232,19 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn should_not_allow_deposit_overflowing_balance() -> anyhow::Result<()> {
+        let mut c = Client::create(7);
+        c.deposit(1, Decimal::MAX)?;
+        assert_eq!(
+            c.deposit(2, 1.into()).unwrap_err().to_string(),
+            "Balance overflow for account 7"
+        );
+        assert_eq!(c.total, Decimal::MAX);
+        assert_eq!(c.txs.len(), 1);
+        Ok(())
+    }
+
     #[test]
     fn should_deposit_multiple() -> anyhow::Result<()> {
         let mut c = Client::default();
@@ -286,7 +1267,7 @@ mod tests {
         c.deposit(3, 3.into())?;
         c.is(5., 0., 5.);
 
-        c.withdraw(4.into())?;
+        c.withdraw(10, 4.into())?;
         c.is(1., 0., 1.);
         Ok(())
     }
@@ -305,7 +1286,7 @@ mod tests {
         c.deposit(3, 3.into())?;
         c.is(5., 0., 5.);
 
-        c.withdraw(4.into())?;
+        c.withdraw(10, 4.into())?;
         c.is(1., 0., 1.);
         Ok(())
     }
@@ -324,6 +1305,188 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn should_dispute_again_after_partial_resolve() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        c.deposit(3, 5.into())?;
+        assert_eq!(c.dispute(&3)?, 5.into());
+        c.is(0., 5., 5.);
+        assert_eq!(c.resolve_part(&3, 2.into())?, 2.into());
+        c.is(2., 3., 5.);
+        assert_eq!(c.dispute(&3)?, 2.into());
+        c.is(0., 5., 5.);
+        assert_eq!(
+            c.dispute(&3).unwrap_err().to_string(),
+            "Deposit in state Dispute != Ok"
+        );
+        assert_eq!(c.resolve_part(&3, 1.into())?, 1.into());
+        c.is(1., 4., 5.);
+        assert_eq!(
+            c.resolve_part(&3, 5.into()).unwrap_err().to_string(),
+            "Deposit 3: Resolving more than disputed: 5 > 4"
+        );
+        assert_eq!(c.resolve(&3)?, 4.into());
+        c.is(5., 0., 5.);
+        assert_eq!(
+            c.resolve(&3).unwrap_err().to_string(),
+            "Deposit in state Ok != Dispute"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_dispute_deposit_in_parts() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        c.deposit(3, 5.into())?;
+        assert_eq!(c.dispute_part(&3, 2.into())?, 2.into());
+        c.is(3., 2., 5.);
+        assert_eq!(
+            c.dispute_part(&3, 4.into()).unwrap_err().to_string(),
+            "Deposit 3: Disputing more than remains: 4 > 3"
+        );
+        assert_eq!(c.dispute_part(&3, 3.into())?, 3.into());
+        c.is(0., 5., 5.);
+        assert_eq!(
+            c.dispute_part(&3, 1.into()).unwrap_err().to_string(),
+            "Deposit in state Dispute != Ok"
+        );
+        assert_eq!(c.deposit_rows()[0].dispute_count, 2);
+        assert_eq!(c.chargeback(&3)?, 5.into());
+        c.is_locked(0., 0., 0.);
+        Ok(())
+    }
+
+    #[test]
+    fn should_chargeback_rest_after_partial_resolve() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        c.deposit(1, 2.into())?;
+        c.deposit(3, 5.into())?;
+        c.dispute(&3)?;
+        c.is(2., 5., 7.);
+        c.resolve_part(&3, 2.into())?;
+        c.is(4., 3., 7.);
+        assert_eq!(c.chargeback(&3)?, 3.into());
+        c.is_locked(4., 0., 4.);
+        Ok(())
+    }
+
+    #[test]
+    fn should_fail_resolve_when_held_would_go_negative() -> anyhow::Result<()> {
+        let mut c = Client::create(2);
+        c.deposit(1, 5.into())?;
+        c.dispute(&1)?;
+        // broken state, held funds no longer cover the dispute
+        c.held = 2.into();
+        assert_eq!(c.resolve(&1), Err(ClientError::HeldUnderflow { client: 2 }));
+        assert_eq!(c.resolve(&1).unwrap_err().to_string(), "Held underflow for account 2");
+        c.is(0., 2., 5.);
+        assert_eq!(c.is_disputed(&1), Some(true));
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_chargeback_more_than_held() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        c.deposit(3, 5.into())?;
+        c.dispute(&3)?;
+        c.held = 1.into();
+        assert_eq!(
+            c.chargeback(&3).unwrap_err().to_string(),
+            "Account 0: Not enough funds held: 5 > 1"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_hold_disputed_withdrawal_until_resolved() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        c.deposit(1, 5.into())?;
+        c.withdraw_disputable(2, 3.into())?;
+        c.is(2., 0., 2.);
+        assert_eq!(c.dispute(&2)?, 3.into());
+        c.is(2., 3., 5.);
+        assert_eq!(c.disputed_withdrawals(), 3.into());
+        assert_eq!(c.resolve(&2)?, 3.into());
+        c.is(2., 0., 2.);
+        assert_eq!(c.deposit_rows().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn should_return_disputed_withdrawal_on_chargeback() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        c.deposit(1, 5.into())?;
+        c.withdraw_disputable(2, 5.into())?;
+        c.dispute(&2)?;
+        c.is(0., 5., 5.);
+        assert_eq!(c.chargeback(&2)?, 5.into());
+        c.is(5., 0., 5.);
+        assert_eq!(c.disputed_withdrawals(), 0.into());
+        c.withdraw(3, 1.into())?;
+        c.is(4., 0., 4.);
+        Ok(())
+    }
+
+    #[test]
+    fn should_adjust_even_locked_account() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        c.deposit(1, 5.into())?;
+        c.adjust((-2).into())?;
+        c.is(3., 0., 3.);
+        c.deposit(2, 1.into())?;
+        c.dispute(&2)?;
+        c.chargeback(&2)?;
+        c.adjust(Decimal::from_f64(0.5).unwrap())?;
+        c.is_locked(3.5, 0., 3.5);
+        assert_eq!(
+            c.adjust((-4).into()).unwrap_err().to_string(),
+            "Account 0: Not enough funds available: 4 > 3.5"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_count_disputes_of_deposit() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        c.deposit(3, 5.into())?;
+        c.dispute(&3)?;
+        c.resolve(&3)?;
+        c.dispute(&3)?;
+        let rows = c.deposit_rows();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].dispute_count, 2);
+        assert_eq!(rows[0].state, DepositState::Dispute);
+        Ok(())
+    }
+
+    #[test]
+    fn should_dispute_by_unique_amount() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        c.deposit(1, 2.into())?;
+        c.deposit(2, 3.into())?;
+        assert_eq!(c.dispute_by_amount(3.into())?, 3.into());
+        c.is(2., 3., 5.);
+        assert_eq!(c.disputed_tx_ids(), [2]);
+        assert_eq!(
+            c.dispute_by_amount(3.into()).unwrap_err().to_string(),
+            "Undisputed deposit of 3 not found"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_dispute_most_recent_of_same_amounts() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        c.deposit(9, 2.into())?;
+        c.deposit(4, 2.into())?;
+        c.dispute_by_amount(2.into())?;
+        assert_eq!(c.disputed_tx_ids(), [4]);
+        c.dispute_by_amount(2.into())?;
+        assert_eq!(c.disputed_tx_ids(), [4, 9]);
+        c.is(0., 4., 4.);
+        Ok(())
+    }
+
     #[test]
     fn should_not_allow_resolve_on_resolved() -> anyhow::Result<()> {
         let mut c = Client::default();
@@ -362,7 +1525,7 @@ mod tests {
         c.deposit(3, 5.into())?;
         c.is(5., 0., 5.);
 
-        c.withdraw(4.into())?;
+        c.withdraw(10, 4.into())?;
         c.is(1., 0., 1.);
         c.deposit(4, 7.into())?;
         c.is(8., 0., 8.);
@@ -380,7 +1543,7 @@ mod tests {
         let mut c = Client::default();
         c.deposit(3, 5.into())?;
         c.is(5., 0., 5.);
-        c.withdraw(4.into())?;
+        c.withdraw(10, 4.into())?;
         c.is(1., 0., 1.);
         assert_eq!(
             c.dispute(&3).unwrap_err().to_string(),
@@ -389,6 +1552,31 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn should_not_allow_withdraw_leaving_dust() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        c.deposit(1, 10.into())?;
+        let threshold = Decimal::from_f64(0.01).unwrap();
+        assert_eq!(
+            c.withdraw_leaving_no_dust(10, Decimal::from_f64(9.9999).unwrap(), threshold, false)
+                .unwrap_err()
+                .to_string(),
+            "Account 0: Withdrawal of 9.9999 would leave dust balance 0.0001"
+        );
+        c.is(10., 0., 10.);
+        c.withdraw_leaving_no_dust(11, Decimal::from_f64(9.99).unwrap(), threshold, false)?;
+        c.is(0.01, 0., 0.01);
+        c.withdraw_leaving_no_dust(12, Decimal::from_f64(0.01).unwrap(), threshold, false)?;
+        c.is(0., 0., 0.);
+        assert_eq!(
+            c.withdraw_leaving_no_dust(13, 1.into(), threshold, false)
+                .unwrap_err()
+                .to_string(),
+            "Account 0: Not enough funds available: 1 > 0.00"
+        );
+        Ok(())
+    }
+
     #[test]
     fn should_not_allow_withdraw_when_not_enough() -> anyhow::Result<()> {
         let mut c = Client::default();
@@ -397,7 +1585,7 @@ mod tests {
         c.deposit(2, 2.into())?;
         c.is(3., 0., 3.);
         assert_eq!(
-            c.withdraw(4.into()).unwrap_err().to_string(),
+            c.withdraw(10, 4.into()).unwrap_err().to_string(),
             "Account 0: Not enough funds available: 4 > 3"
         );
         Ok(())
@@ -413,7 +1601,7 @@ mod tests {
         c.dispute(&2)?;
         c.is(1., 2., 3.);
         assert_eq!(
-            c.withdraw(2.into()).unwrap_err().to_string(),
+            c.withdraw(10, 2.into()).unwrap_err().to_string(),
             "Account 0: Not enough funds available: 2 > 1"
         );
         Ok(())
@@ -455,6 +1643,164 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn should_not_allow_withdraw_on_frozen_until_unfrozen() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        c.deposit(1, 3.into())?;
+        assert_eq!(c.unfreeze(), Err(ClientError::NotFrozen { client: 0 }));
+        c.freeze()?;
+        assert_eq!(c.freeze().unwrap_err().to_string(), "Account 0 is already frozen");
+        assert_eq!(
+            c.withdraw(10, 1.into()).unwrap_err().to_string(),
+            "Account 0 is frozen"
+        );
+        c.unfreeze()?;
+        assert_eq!(c.unfreeze().unwrap_err().to_string(), "Account 0 is not frozen");
+        c.withdraw(10, 1.into())?;
+        c.is(2., 0., 2.);
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_unlock_chargedback_on_unfreeze() -> anyhow::Result<()> {
+        let mut c = Client::default();
+        c.deposit(1, 3.into())?;
+        c.dispute(&1)?;
+        c.chargeback(&1)?;
+        c.freeze()?;
+        c.unfreeze()?;
+        assert_eq!(
+            c.deposit(2, 1.into()).unwrap_err().to_string(),
+            "Account 0 is locked"
+        );
+        c.is_locked(0., 0., 0.);
+        Ok(())
+    }
+
+    #[test]
+    fn should_serialize_lock_reason() -> anyhow::Result<()> {
+        let to_csv = |c: &Client| {
+            let mut wtr = csv::Writer::from_writer(vec![]);
+            wtr.serialize(c.view(ViewOptions {
+                lock_reason: true,
+                ..Default::default()
+            }))
+            .unwrap();
+            String::from_utf8(wtr.into_inner().unwrap()).unwrap()
+        };
+        let mut c = Client::create(7);
+        c.deposit(1, 3.into())?;
+        assert_eq!(
+            to_csv(&c),
+            "client,available,held,total,locked,lock_reason\n7,3.0,0.0,3.0,false,\n"
+        );
+        c.freeze()?;
+        assert_eq!(c.lock_reason(), Some(LockReason::AdminFreeze));
+        assert_eq!(
+            to_csv(&c),
+            "client,available,held,total,locked,lock_reason\n7,3.0,0.0,3.0,true,admin_freeze\n"
+        );
+        c.unfreeze()?;
+        assert_eq!(c.lock_reason(), None);
+        c.dispute(&1)?;
+        c.chargeback(&1)?;
+        c.freeze()?;
+        assert_eq!(c.lock_reason(), Some(LockReason::Chargeback));
+        assert_eq!(
+            to_csv(&c),
+            "client,available,held,total,locked,lock_reason\n7,0.0,0.0,0.0,true,chargeback\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_detect_inconsistent_funds() -> anyhow::Result<()> {
+        let mut c = Client::create(7);
+        c.deposit(1, 3.into())?;
+        c.ensure_consistent()?;
+        c.held += Decimal::from(1);
+        assert_eq!(
+            c.ensure_consistent().unwrap_err().to_string(),
+            "Account 7: Inconsistent funds: available 3 + held 1 != total 3"
+        );
+        c.held = (-1).into();
+        assert_eq!(
+            c.ensure_consistent().unwrap_err().to_string(),
+            "Account 7: Negative funds: available 3, held -1"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_round_to_chosen_precision() -> anyhow::Result<()> {
+        let mut c = Client::create(7);
+        c.deposit(1, Decimal::from_f64(1.2345).unwrap())?;
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        wtr.serialize(c.view(ViewOptions {
+            precision: Some(2),
+            ..Default::default()
+        }))?;
+        assert_eq!(
+            String::from_utf8(wtr.into_inner()?)?,
+            "client,available,held,total,locked\n7,1.23,0.0,1.23,false\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_merge_clients_with_disjoint_deposits() -> anyhow::Result<()> {
+        let mut c = Client::create(1);
+        c.deposit(1, 3.into())?;
+        let mut other = Client::create(2);
+        other.deposit(2, 2.into())?;
+        other.deposit(3, 1.into())?;
+        other.dispute(&3)?;
+        other.chargeback(&3)?;
+        c.merge_from(other)?;
+        c.is_locked(5., 0., 5.);
+        assert_eq!(c.txs.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn should_undo_last_tx_of_merged_client() -> anyhow::Result<()> {
+        let mut c = Client::create(1);
+        c.deposit(1, 3.into())?;
+        c.deposit(2, 1.into())?;
+        let mut other = Client::create(2);
+        other.deposit(3, 2.into())?;
+        other.deposit(4, 4.into())?;
+        c.merge_from(other)?;
+        assert_eq!(c.undo_last()?, 4);
+        assert_eq!(c.undo_last()?, 3);
+        assert_eq!(c.undo_last()?, 2);
+        c.is(3., 0., 3.);
+        let mut other = Client::create(2);
+        other.deposit(5, 2.into())?;
+        other.withdraw(6, 1.into())?;
+        c.merge_from(other)?;
+        assert_eq!(c.undo_last()?, 6);
+        assert_eq!(c.undo_last()?, 5);
+        assert_eq!(c.undo_last()?, 1);
+        c.is(0., 0., 0.);
+        assert_eq!((c.deposit_count, c.withdrawal_count), (0, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_merge_clients_with_same_deposit_id() -> anyhow::Result<()> {
+        let mut c = Client::create(1);
+        c.deposit(1, 3.into())?;
+        let mut other = Client::create(2);
+        other.deposit(1, 2.into())?;
+        assert_eq!(
+            c.merge_from(other).unwrap_err().to_string(),
+            "Cannot merge account 2 into 1: both have deposit 1"
+        );
+        c.is(3., 0., 3.);
+        Ok(())
+    }
+
     #[test]
     fn should_not_allow_any_tx_on_locked(
     ) -> anyhow::Result<()> {
@@ -474,7 +1820,7 @@ mod tests {
             "Account 0 is locked"
         );
         assert_eq!(
-            c.withdraw(1.into()).unwrap_err().to_string(),
+            c.withdraw(10, 1.into()).unwrap_err().to_string(),
             "Account 0 is locked"
         );
         assert_eq!(
@@ -491,4 +1837,47 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn should_tell_error_reasons_apart() -> anyhow::Result<()> {
+        let mut c = Client::create(0);
+        c.deposit(1, 1.into())?;
+        assert_eq!(
+            c.withdraw(2, 2.into()),
+            Err(ClientError::InsufficientFunds {
+                client: 0,
+                funds: "available",
+                needed: 2.into(),
+                present: 1.into(),
+            })
+        );
+        assert_eq!(c.dispute(&3), Err(ClientError::NotFound { tx: 3 }));
+        assert_eq!(
+            c.resolve(&1),
+            Err(ClientError::BadState { state: DepositState::Ok, expected: DepositState::Dispute })
+        );
+        c.freeze()?;
+        assert_eq!(
+            c.deposit(4, 1.into()),
+            Err(ClientError::Locked { client: 0, reason: LockReason::AdminFreeze })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_create_client_with_balance() -> anyhow::Result<()> {
+        let mut c = Client::with_balance(3, 2.into(), 1.into(), 3.into())?;
+        c.is(2.0, 1.0, 3.0);
+        c.withdraw(1, 2.into())?;
+        c.is(0.0, 1.0, 1.0);
+        assert_eq!(
+            Client::with_balance(3, 2.into(), 1.into(), 2.into()).unwrap_err().to_string(),
+            "Account 3: Inconsistent funds: available 2 + held 1 != total 2"
+        );
+        assert_eq!(
+            Client::with_balance(3, (-1).into(), 1.into(), 0.into()).unwrap_err().to_string(),
+            "Account 3: Negative funds: available -1, held 1"
+        );
+        Ok(())
+    }
 }
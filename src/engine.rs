@@ -1,130 +1,3227 @@
-use anyhow::anyhow;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use anyhow::{anyhow, bail, ensure, Context};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
 
-use crate::client::Client;
-use crate::tx::Tx;
+use crate::amount;
+use crate::client::{Client, ClientSnapshot, ClientView, DrainAction};
+use crate::fast_csv::FastCsvReader;
+use crate::input::{
+    self, HashingReader, InputFormat, LineLimitReader, ProgressReader, RetryReader,
+};
+use crate::output::{self, OutputFormat, SortKey, ViewOptions};
+use crate::settle::{self, SettlePolicy, Settlement};
+use crate::stats::{RunSummary, Stats};
+use crate::tx::{self, Policy, Tx, TxRow, TxType};
 
+#[derive(Debug, Default)]
+pub struct Config {
+    pub(crate) input_format: InputFormat,
+    pub(crate) min_tx_id: Option<u32>,
+    pub(crate) max_tx_id: Option<u32>,
+    pub(crate) assert_balanced: bool,
+    pub(crate) allow_fractions: bool,
+    pub(crate) strict_order: bool,
+    pub(crate) view: ViewOptions,
+    pub(crate) drain_disputes: Option<DrainAction>,
+    pub(crate) fast_parse: bool,
+    pub(crate) sort: SortKey,
+    pub(crate) policy: Policy,
+    pub(crate) checksum: Option<String>,
+    pub(crate) cdc: Option<PathBuf>,
+    pub(crate) max_line_length: Option<usize>,
+    pub(crate) settle: Option<SettlePolicy>,
+    pub(crate) settle_overrides: Option<PathBuf>,
+    pub(crate) holds_report: Option<PathBuf>,
+    /// Total held funds are recorded after every this many rows, after every row if not given.
+    pub(crate) holds_interval: Option<NonZeroU64>,
+    pub(crate) deposits_report: Option<PathBuf>,
+    /// CSV file with every tx still being disputed, by client.
+    pub(crate) disputes_report: Option<PathBuf>,
+    /// CSV file with every operation applied to balances and the balances after it.
+    pub(crate) ledger: Option<PathBuf>,
+    /// Rows are applied in batches of this size, batch leaving any client inconsistent is rolled back.
+    pub(crate) batch_commit: Option<NonZeroU64>,
+    pub(crate) summary_json: Option<PathBuf>,
+    /// Output file, stdout if not given.
+    pub(crate) output: Option<PathBuf>,
+    /// Input file which cannot be read is skipped instead of aborting the run.
+    pub(crate) skip_bad_inputs: bool,
+    pub(crate) merge_accounts: Option<PathBuf>,
+    pub(crate) json_map: bool,
+    pub(crate) output_format: OutputFormat,
+    pub(crate) pretty: bool,
+    /// Unlocked clients without any funds are left out of the output.
+    pub(crate) hide_empty: bool,
+    /// How many times failed opening or reading of the input is retried.
+    pub(crate) io_retries: u32,
+    /// Output is flushed after every this many clients, not only at the end.
+    pub(crate) flush_every: Option<usize>,
+    /// Run fails if any client's total ends below it.
+    pub(crate) balance_floor: Option<Decimal>,
+    /// Run fails if any client's total ends above it.
+    pub(crate) balance_ceiling: Option<Decimal>,
+    /// Skipped rows are logged to stderr with the reason.
+    pub(crate) verbose: bool,
+    /// CSV input is processed by this many threads, each owning a shard of clients,
+    /// serially if there are less than two.
+    pub(crate) workers: usize,
+    /// Disputes, resolves and chargebacks of txs not seen yet wait for them, up to this many.
+    pub(crate) pending_disputes: Option<usize>,
+    /// Run is aborted as soon as a tx leaves its client's funds not adding up.
+    pub(crate) strict: bool,
+    /// Counts of rows by tx type, skipped rows and locked accounts are printed to stderr.
+    pub(crate) stats: bool,
+    /// CSV file with every applied tx and its row of the input, newly applied ones are appended.
+    /// Those are applied again before processing the input, whose rows up to the last of them
+    /// are skipped then, so rerunning on the same input, e.g. after a crash, continues the run.
+    pub(crate) checkpoint: Option<PathBuf>,
+    /// Output CSV of a previous run, its clients are loaded before processing the input.
+    pub(crate) seed: Option<PathBuf>,
+    /// CSV with the balances before and after every applied tx and the reason of every skipped one.
+    pub(crate) audit: Option<PathBuf>,
+    /// Progress of reading the input is reported to stderr.
+    pub(crate) progress: bool,
+    /// Delimiter of the CSV input, comma if not given.
+    pub(crate) delimiter: Option<u8>,
+    /// Both input and output are tab-separated.
+    pub(crate) tsv: bool,
+    /// Run is aborted on the first row which cannot be parsed or applied, instead of skipping it.
+    pub(crate) fail_fast: bool,
+}
+
+impl Config {
+    pub(crate) fn delimiter(&self) -> u8 {
+        if self.tsv {
+            return b'\t';
+        }
+        self.delimiter.unwrap_or(b',')
+    }
+
+    fn output_delimiter(&self) -> u8 {
+        if self.tsv {
+            b'\t'
+        } else {
+            b','
+        }
+    }
+
+    /// Workers apply txs on their own threads, bypassing what is done per row on this one.
+    /// Audit rows are written right away, so those of a rolled back batch cannot be taken back.
+    fn validate(&self) -> anyhow::Result<()> {
+        ensure!(
+            self.batch_commit.is_none() || self.audit.is_none(),
+            "Batch commit cannot be combined with audit"
+        );
+        // txs waiting for their deposit are not applied yet, so they would be lost
+        ensure!(
+            self.checkpoint.is_none() || self.pending_disputes.is_none(),
+            "Checkpoint cannot be combined with pending disputes"
+        );
+        if self.workers < 2 {
+            return Ok(());
+        }
+        let unsupported = [
+            ("fast parse", self.fast_parse),
+            ("batch commit", self.batch_commit.is_some()),
+            ("holds report", self.holds_report.is_some()),
+            ("ledger", self.ledger.is_some()),
+            ("pending disputes", self.pending_disputes.is_some()),
+            ("checkpoint", self.checkpoint.is_some()),
+            ("audit", self.audit.is_some()),
+            ("strict", self.strict),
+            ("fail fast", self.fail_fast),
+        ];
+        if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+            bail!("Workers cannot be combined with {}", name);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Engine {
+    clients: HashMap<u16, Client>,
+    config: Config,
+    stats: Stats,
+    // ids of deposits seen so far, tracked only in strict order mode
+    deposit_ids: HashSet<u32>,
+    // ids of deposits and withdrawals seen so far
+    tx_ids: HashSet<u32>,
+    // (row, total held) series, recorded only for holds report
+    holds: Vec<(u64, Decimal)>,
+    // applied operations, recorded only for ledger
+    ledger: Vec<LedgerRow>,
+    // ids of clients in order of their creation
+    first_seen: Vec<u16>,
+    // state before the current batch, kept only in batch commit mode
+    batch_start: Option<BatchStart>,
+    // disputes, resolves and chargebacks waiting for their tx, in order of the input
+    pending: Vec<Tx>,
+    // applied txs are appended to it, opened only with checkpoint
+    checkpoint: Option<csv::Writer<File>>,
+    // rows of the input applied according to the checkpoint
+    checkpointed_rows: u64,
+    // opened only with audit
+    audit: Option<csv::Writer<File>>,
+    // clients have been loaded from the seed, so processing again does not load them twice
+    seeded: bool,
+}
+
+struct BatchStart {
+    // clients touched by the batch as they were before it, `None` if created by it
+    clients: HashMap<u16, Option<Client>>,
+    stats: Stats,
+    // ids first seen in the batch
+    tx_ids: Vec<u32>,
+    deposit_ids: Vec<u32>,
+    first_seen: usize,
+    ledger: usize,
+    holds: usize,
+    pending: Vec<Tx>,
+    // txs applied in the batch, appended to the checkpoint once it is committed
+    checkpoint: Vec<CheckpointRow>,
+}
+
+/// Txs are routed to a worker in batches of this size, as sending them one by one is slow.
+const SHARD_BATCH: usize = 4096;
+
+/// Batches routed to a worker, but not processed yet.
+const SHARD_CHANNEL_CAPACITY: usize = 16;
+
+/// Clients owned by a worker thread, with stats of the txs it has processed.
 #[derive(Default)]
-pub(crate) struct Engine {
+struct Shard {
     clients: HashMap<u16, Client>,
+    stats: Stats,
+    // (row, client id) of clients created by the worker
+    first_seen: Vec<(u64, u16)>,
+}
+
+impl Shard {
+    fn process(&mut self, batches: Receiver<Vec<(u64, Tx)>>, policy: &Policy, verbose: bool) {
+        for (row, tx) in batches.into_iter().flatten() {
+            let is_new = !self.clients.contains_key(&tx.client_id);
+            let result = apply_tx(&mut self.clients, &mut self.stats, &tx, policy);
+            if is_new && self.clients.contains_key(&tx.client_id) {
+                self.first_seen.push((row, tx.client_id));
+            }
+            if let Err(e) = result {
+                self.stats.skipped += 1;
+                if verbose {
+                    eprintln!("Error: {}", e)
+                }
+            }
+        }
+    }
+}
+
+/// Operation applied to client's balances, with the balances after it.
+#[derive(Debug, PartialEq, Serialize)]
+struct LedgerRow {
+    client: u16,
+    tx: u32,
+    operation: &'static str,
+    /// Amount moved by the operation.
+    delta: Decimal,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+}
+
+/// Applied tx with its row of the input, as recorded in the checkpoint.
+/// Amounts are written as they are, not as floats, so they are read back exactly.
+#[derive(Debug, PartialEq, Serialize)]
+struct CheckpointRow {
+    row: u64,
+    #[serde(rename = "type")]
+    tx_type: &'static str,
+    client: u16,
+    tx: u32,
+    amount: Option<String>,
+    available_delta: Option<String>,
+    held_delta: Option<String>,
+    total_delta: Option<String>,
+    currency: Option<String>,
 }
 
-impl Engine {
-    pub(crate) fn run(&mut self, input_file: PathBuf) -> anyhow::Result<()> {
-        self.process_file(input_file)?;
-        Ok(self.output()?)
+impl CheckpointRow {
+    fn new(row: u64, tx: &Tx) -> Self {
+        let (amount, deltas) = match &tx.tx_type {
+            TxType::Deposit { amount }
+            | TxType::Withdrawal { amount }
+            | TxType::Adjustment { amount } => (Some(*amount), None),
+            TxType::Dispute { amount } => (*amount, None),
+            TxType::Delta {
+                available_delta,
+                held_delta,
+                total_delta,
+            } => (None, Some([available_delta, held_delta, total_delta])),
+            TxType::Resolve | TxType::Chargeback | TxType::Freeze | TxType::Unfreeze => {
+                (None, None)
+            }
+        };
+        let delta = |i: usize| deltas.map(|d| d[i].to_string());
+        CheckpointRow {
+            row,
+            tx_type: tx.tx_type.name(),
+            client: tx.client_id,
+            tx: tx.tx_id,
+            amount: amount.map(|a| a.to_string()),
+            available_delta: delta(0),
+            held_delta: delta(1),
+            total_delta: delta(2),
+            currency: tx.currency.clone(),
+        }
+    }
+}
+
+/// Row of the input a tx of the checkpoint comes from, the tx itself is read separately.
+#[derive(Deserialize)]
+struct CheckpointedRow {
+    row: u64,
+}
+
+/// Tx with the client's balances before and after it, or the reason it has been skipped.
+/// Rows which cannot be parsed have no tx, skipped rows have no balances.
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct AuditRow {
+    tx: Option<u32>,
+    #[serde(rename = "type")]
+    tx_type: Option<&'static str>,
+    client: Option<u16>,
+    available_before: Option<Decimal>,
+    held_before: Option<Decimal>,
+    total_before: Option<Decimal>,
+    available_after: Option<Decimal>,
+    held_after: Option<Decimal>,
+    total_after: Option<Decimal>,
+    rejection: Option<String>,
+}
+
+impl AuditRow {
+    fn new(tx: &Tx) -> Self {
+        AuditRow {
+            tx: Some(tx.tx_id),
+            tx_type: Some(tx.tx_type.name()),
+            client: Some(tx.client_id),
+            ..Default::default()
+        }
+    }
+}
+
+/// Client ids ordered by their last touch, so the least recently touched one might be evicted.
+#[derive(Default)]
+struct Lru {
+    tick: u64,
+    by_tick: BTreeMap<u64, u16>,
+    ticks: HashMap<u16, u64>,
+}
+
+impl Lru {
+    fn touch(&mut self, client_id: u16) {
+        if let Some(tick) = self.ticks.insert(client_id, self.tick) {
+            self.by_tick.remove(&tick);
+        }
+        self.by_tick.insert(self.tick, client_id);
+        self.tick += 1;
+    }
+
+    fn pop(&mut self) -> Option<u16> {
+        let (_, client_id) = self.by_tick.pop_first()?;
+        self.ticks.remove(&client_id);
+        Some(client_id)
+    }
+}
+
+impl Engine {
+    /// Fails if the config combines options which cannot work together.
+    pub fn new(config: Config) -> anyhow::Result<Self> {
+        config.validate()?;
+        Ok(Engine {
+            config,
+            ..Default::default()
+        })
+    }
+
+    /// Forgets all the processed txs, keeping the config.
+    pub fn reset(&mut self) {
+        let config = std::mem::take(&mut self.config);
+        *self = Engine {
+            config,
+            ..Default::default()
+        };
+    }
+
+    /// Processes the input files in order, or stdin if there are none, and writes the output.
+    pub fn run(&mut self, input_files: Vec<PathBuf>) -> anyhow::Result<()> {
+        self.process(input_files)?;
+        self.output()
+    }
+
+    /// Processes the input files like [`Engine::process`], but writes neither the output
+    /// nor any reports, e.g. to validate them before processing for real.
+    /// Fails if any row is invalid, i.e. cannot be parsed or applied.
+    pub(crate) fn check(mut self, input_files: Vec<PathBuf>) -> anyhow::Result<()> {
+        self.seed_once()?;
+        match input_files.is_empty() {
+            true => self.process_stdin()?,
+            false => self.process_files(input_files)?,
+        }
+        self.skip_pending()?;
+        let invalid = self.stats.skipped;
+        eprintln!("valid {}, invalid {}", self.stats.rows - invalid, invalid);
+        ensure!(invalid == 0, "Found {} invalid rows of {}", invalid, self.stats.rows);
+        Ok(())
+    }
+
+    /// Processes the input files in order, or stdin if there are none, and writes the reports,
+    /// but not the output, so the clients might be taken with [`Engine::into_clients`].
+    pub fn process(&mut self, input_files: Vec<PathBuf>) -> anyhow::Result<()> {
+        self.seed_once()?;
+        if let (Some(path), None) = (&self.config.audit, &self.audit) {
+            self.audit = Some(csv::Writer::from_path(path)?);
+        }
+        let opening_total = self.sum_of_totals();
+        self.stats.held = self.clients.values().map(|c| c.held).sum();
+        if let (Some(path), None) = (self.config.checkpoint.clone(), &self.checkpoint) {
+            if path.exists() {
+                self.replay_checkpoint(&path)
+                    .with_context(|| format!("Invalid checkpoint {}", path.display()))?;
+            }
+            let file = File::options().create(true).append(true).open(&path)?;
+            let is_new = file.metadata()?.len() == 0;
+            self.checkpoint = Some(csv::WriterBuilder::new().has_headers(is_new).from_writer(file));
+        }
+        match input_files.is_empty() {
+            true => self.process_stdin()?,
+            false => self.process_files(input_files)?,
+        }
+        self.skip_pending()?;
+        if let Some(checkpoint) = &mut self.checkpoint {
+            checkpoint.flush()?;
+        }
+        if let Some(audit) = &mut self.audit {
+            audit.flush()?;
+        }
+        if let Some(path) = &self.config.merge_accounts {
+            let merges = read_merges(path)?;
+            self.merge_accounts(&merges)?;
+        }
+        if let Some(action) = self.config.drain_disputes {
+            self.drain_disputes(action);
+        }
+        if let Some(policy) = self.config.settle {
+            let overrides = match &self.config.settle_overrides {
+                Some(path) => settle::read_overrides(path)?,
+                None => HashMap::new(),
+            };
+            for s in self.settle(policy, &overrides) {
+                eprintln!("Settled client {} with {:?}: {}", s.client_id, s.policy, s.amount);
+            }
+        }
+        if self.config.assert_balanced {
+            self.ensure_balanced(opening_total)?;
+        }
+        self.ensure_within_bounds()?;
+        if let Some(path) = &self.config.cdc {
+            self.write_cdc(File::create(path)?)?;
+        }
+        if let Some(path) = &self.config.holds_report {
+            self.write_holds(File::create(path)?)?;
+        }
+        if let Some(path) = &self.config.deposits_report {
+            self.write_deposits(File::create(path)?)?;
+        }
+        if let Some(path) = &self.config.disputes_report {
+            self.write_disputes(File::create(path)?)?;
+        }
+        if let Some(path) = &self.config.ledger {
+            self.write_ledger(File::create(path)?)?;
+        }
+        if self.config.stats {
+            eprintln!("{}", self.stats_line());
+        }
+        if let Some(path) = &self.config.summary_json {
+            let mut file = File::create(path)?;
+            serde_json::to_writer_pretty(&mut file, &self.summary())?;
+            writeln!(file)?;
+        }
+        Ok(())
+    }
+
+    /// Applies txs recorded in the checkpoint by an earlier run on the same input,
+    /// so its rows up to the last of them are not applied again.
+    fn replay_checkpoint(&mut self, path: &Path) -> anyhow::Result<()> {
+        let mut rdr = csv::Reader::from_path(path)?;
+        let headers = rdr.headers()?.clone();
+        for record in rdr.records() {
+            let record = record?;
+            let CheckpointedRow { row } = record.deserialize(Some(&headers))?;
+            let tx = record
+                .deserialize::<TxRow>(Some(&headers))?
+                .into_tx(self.config.allow_fractions)?;
+            self.process_row(Ok::<_, anyhow::Error>(tx)).with_context(|| format!("Row {}", row))?;
+            self.checkpointed_rows = row;
+        }
+        Ok(())
+    }
+
+    /// Takes the processed clients, for callers which want them as data instead of the output.
+    pub fn into_clients(self) -> HashMap<u16, Client> {
+        self.clients
+    }
+
+    /// Processes a single tx like a row of the input, but fails if it cannot be processed.
+    /// Returns balances of its client after it.
+    pub fn apply(&mut self, tx: Tx) -> anyhow::Result<ClientSnapshot> {
+        let client_id = tx.client_id;
+        self.stats.rows += 1;
+        self.stats.count(&tx.tx_type);
+        if let Err(e) = self.process_row(Ok::<_, anyhow::Error>(tx)) {
+            self.stats.skipped += 1;
+            return Err(e);
+        }
+        match self.clients.get(&client_id) {
+            Some(client) => Ok(client.snapshot()),
+            None => Err(anyhow!("Account {} not found", client_id)),
+        }
+    }
+
+    /// Processes the txs like rows of the input, e.g. generated in memory,
+    /// so the ones which cannot be processed are skipped.
+    pub fn process_txs<I: IntoIterator<Item = Tx>>(&mut self, txs: I) -> anyhow::Result<()> {
+        for tx in txs {
+            self.handle_row(Ok::<_, anyhow::Error>(tx))?;
+        }
+        self.commit_batch()
+    }
+
+    /// Processes the files as one input, so later ones might refer to txs of the earlier ones.
+    /// File which cannot be read aborts the run, unless bad inputs are skipped.
+    fn process_files(&mut self, input_files: Vec<PathBuf>) -> anyhow::Result<()> {
+        for input_file in input_files {
+            let name = input_file.display().to_string();
+            match self.process_file(input_file) {
+                Err(e) if self.config.skip_bad_inputs => eprintln!("Skipped {}: {}", name, e),
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Processes CSV input keeping at most `max_clients` in memory, for unbounded streams.
+    /// When there are more, the least recently touched client is evicted, i.e. written
+    /// to the output and dropped, so its deposits cannot be disputed anymore.
+    /// Client touched again after its eviction starts from scratch and is written again.
+    /// Clients left at the end are written sorted by id.
+    /// Tx ids are still tracked to skip duplicates.
+    /// Evicted clients cannot be rolled back, so it is not allowed in batch commit mode.
+    pub fn process_reader_with_limit<R: Read, W: Write>(
+        &mut self,
+        reader: R,
+        max_clients: usize,
+        writer: W,
+    ) -> anyhow::Result<()> {
+        ensure!(max_clients > 0, "Max clients must be positive");
+        ensure!(
+            self.config.batch_commit.is_none(),
+            "Max clients cannot be combined with batch commit"
+        );
+        let mut lru = Lru::default();
+        let mut wtr = csv::Writer::from_writer(writer);
+        for result in self.csv_rows(reader)? {
+            let client_id = result.as_ref().ok().map(|tx| tx.client_id);
+            self.handle_row(result)?;
+            if let Some(client_id) = client_id.filter(|id| self.clients.contains_key(id)) {
+                lru.touch(client_id);
+            }
+            while self.clients.len() > max_clients {
+                let Some(client_id) = lru.pop() else {
+                    break;
+                };
+                if let Some(client) = self.clients.remove(&client_id) {
+                    wtr.serialize(client.view(self.config.view))?;
+                }
+            }
+        }
+        for c in self.clients_snapshot_sorted(SortKey::Id) {
+            wtr.serialize(c.view(self.config.view))?;
+        }
+        Ok(wtr.flush()?)
+    }
+
+    fn process_file(&mut self, input_file: PathBuf) -> anyhow::Result<()> {
+        let retries = self.config.io_retries;
+        let file = input::retry(retries, || File::open(&input_file))?;
+        let len = file.metadata()?.len();
+        let file = RetryReader::new(file, retries);
+        let name = input_file.display().to_string();
+        match self.config.progress {
+            true => self.process_input(&name, ProgressReader::new(file, Some(len), io::stderr())),
+            false => self.process_input(&name, file),
+        }
+    }
+
+    fn process_stdin(&mut self) -> anyhow::Result<()> {
+        let stdin = io::stdin().lock();
+        match self.config.progress {
+            true => self.process_input("stdin", ProgressReader::new(stdin, None, io::stderr())),
+            false => self.process_input("stdin", stdin),
+        }
+    }
+
+    /// Processes the input verifying its checksum, if configured.
+    fn process_input<R: Read>(&mut self, name: &str, input: R) -> anyhow::Result<()> {
+        match self.config.checksum.clone() {
+            Some(expected) => {
+                // hashing while processing, so the input is read once
+                let mut reader = HashingReader::new(input);
+                self.process_reader(BufReader::new(&mut reader))?;
+                let actual = reader.finish()?;
+                ensure!(
+                    actual.eq_ignore_ascii_case(&expected),
+                    "Checksum mismatch for {}: expected {}, got {}",
+                    name,
+                    expected,
+                    actual,
+                );
+                Ok(())
+            }
+            None => self.process_reader(BufReader::new(input)),
+        }
+    }
+
+    fn process_reader<R: BufRead>(&mut self, mut reader: R) -> anyhow::Result<()> {
+        let format = match self.config.input_format {
+            InputFormat::Auto => InputFormat::sniff(&mut reader)?,
+            format => format,
+        };
+        match self.config.max_line_length {
+            // JSON array is a single document, lines mean nothing there
+            Some(max) if format != InputFormat::Json => {
+                self.process_format(format, BufReader::new(LineLimitReader::new(reader, max)))
+            }
+            _ => self.process_format(format, reader),
+        }?;
+        self.commit_batch()
+    }
+
+    /// Builder of CSV reader of the input, its BOM is skipped by `csv` itself.
+    fn csv_reader(&self) -> csv::ReaderBuilder {
+        let mut builder = csv::ReaderBuilder::new();
+        builder.trim(csv::Trim::All).delimiter(self.config.delimiter());
+        builder
+    }
+
+    /// Txs of CSV input, its header has to have all the [`tx::COLUMNS`].
+    /// Trailing fields might be left out, so a row without amount is reported as such.
+    fn csv_rows<R: Read>(
+        &self,
+        reader: R,
+    ) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Tx>>> {
+        let mut rdr = self.csv_reader().flexible(true).from_reader(reader);
+        let headers = rdr.headers()?.clone();
+        tx::ensure_columns(&headers)?;
+        let allow_fractions = self.config.allow_fractions;
+        Ok(rdr.into_records().map(move |result| {
+            let mut record = result?;
+            ensure!(
+                record.len() <= headers.len(),
+                "Found record with {} fields, but the header has {}",
+                record.len(),
+                headers.len()
+            );
+            while record.len() < headers.len() {
+                record.push_field("");
+            }
+            record.deserialize::<TxRow>(Some(&headers))?.into_tx(allow_fractions)
+        }))
+    }
+
+    fn process_format<R: BufRead>(&mut self, format: InputFormat, reader: R) -> anyhow::Result<()> {
+        match format {
+            InputFormat::Csv if self.config.workers > 1 => {
+                let rows = self.csv_rows(reader)?;
+                self.process_sharded(rows, self.config.workers)?;
+            }
+            InputFormat::Csv if self.config.fast_parse => {
+                let delimiter = self.config.delimiter();
+                for result in FastCsvReader::new(reader, delimiter, self.config.allow_fractions)? {
+                    self.handle_row(result)?;
+                }
+            }
+            InputFormat::Csv => {
+                for result in self.csv_rows(reader)? {
+                    self.handle_row(result)?;
+                }
+            }
+            InputFormat::Json => {
+                // the whole array is buffered, use NDJSON for big inputs,
+                // its elements are deserialized one by one to skip only the wrong ones
+                let txs: Vec<serde_json::Value> = serde_json::from_reader(reader)?;
+                for tx in txs {
+                    let tx = serde_json::from_value::<TxRow>(tx)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|row| row.into_tx(self.config.allow_fractions));
+                    self.handle_row(tx)?;
+                }
+            }
+            InputFormat::Ndjson => {
+                for line in reader.lines() {
+                    let line = line?;
+                    if !line.trim().is_empty() {
+                        let tx = serde_json::from_str::<TxRow>(&line)
+                            .map_err(anyhow::Error::from)
+                            .and_then(|row| row.into_tx(self.config.allow_fractions));
+                        self.handle_row(tx)?;
+                    }
+                }
+            }
+            InputFormat::Auto => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Processes the row skipping it on error, e.g. when the row cannot be parsed.
+    /// Returns error only when the whole run should be aborted, e.g. on IO error while reading.
+    fn handle_row<E>(&mut self, row: Result<Tx, E>) -> anyhow::Result<()>
+    where
+        anyhow::Error: From<E>,
+    {
+        if self.config.batch_commit.is_some() && self.batch_start.is_none() {
+            self.batch_start = Some(BatchStart {
+                clients: HashMap::new(),
+                stats: self.stats.clone(),
+                tx_ids: vec![],
+                deposit_ids: vec![],
+                first_seen: self.first_seen.len(),
+                ledger: self.ledger.len(),
+                holds: self.holds.len(),
+                pending: self.pending.clone(),
+                checkpoint: vec![],
+            });
+        }
+        self.stats.rows += 1;
+        let row = row.map(|tx| tx.normalized(&self.config.policy));
+        if let Ok(tx) = &row {
+            self.stats.count(&tx.tx_type);
+            if self.config.strict_order && self.in_tx_id_range(tx.tx_id) {
+                self.ensure_order(tx)?;
+            }
+        }
+        let client_id = row.as_ref().ok().map(|tx| tx.client_id);
+        let audit_row = self
+            .audit
+            .is_some()
+            .then(|| row.as_ref().ok().map(AuditRow::new).unwrap_or_default());
+        if self.stats.rows <= self.checkpointed_rows {
+            // applied by the earlier run, it has been applied again from the checkpoint
+            self.stats.checkpointed += 1;
+        } else if let Err(e) = self.process_row(row) {
+            if io_error_kind(&e).is_some() {
+                return Err(e);
+            }
+            self.audit_rejection(audit_row, &e)?;
+            self.skip(e)?;
+        }
+        if self.config.strict {
+            if let Some(client) = client_id.and_then(|id| self.clients.get(&id)) {
+                client.ensure_consistent().context("Invariant violated")?;
+            }
+        }
+        let holds_interval = self.config.holds_interval.map_or(1, NonZeroU64::get);
+        if self.config.holds_report.is_some() && self.stats.rows.is_multiple_of(holds_interval) {
+            self.holds.push((self.stats.rows, self.stats.held));
+        }
+        if self.config.batch_commit.is_some_and(|n| self.stats.rows.is_multiple_of(n.get())) {
+            self.commit_batch()?;
+        }
+        Ok(())
+    }
+
+    /// Processes the rows by worker threads, each owning clients with the same
+    /// `client_id % workers`, so txs of a client are still applied in order of the input
+    /// and the balances are the same as when processed serially.
+    /// Rows are parsed and checked for duplicates on this thread.
+    fn process_sharded<E>(
+        &mut self,
+        rows: impl Iterator<Item = Result<Tx, E>>,
+        workers: usize,
+    ) -> anyhow::Result<()>
+    where
+        anyhow::Error: From<E>,
+    {
+        let mut shards: Vec<Shard> = (0..workers).map(|_| Shard::default()).collect();
+        for (client_id, client) in self.clients.drain() {
+            shards[usize::from(client_id) % workers].clients.insert(client_id, client);
+        }
+        let policy = self.config.policy.clone();
+        let verbose = self.config.verbose;
+        let result = thread::scope(|scope| {
+            let mut senders = vec![];
+            let mut batches: Vec<Vec<(u64, Tx)>> = vec![vec![]; workers];
+            for shard in shards.iter_mut() {
+                let (sender, receiver) = mpsc::sync_channel(SHARD_CHANNEL_CAPACITY);
+                senders.push(sender);
+                let policy = &policy;
+                scope.spawn(move || shard.process(receiver, policy, verbose));
+            }
+            for row in rows {
+                self.stats.rows += 1;
+                let tx = match row {
+                    Ok(tx) => {
+                        let tx = tx.normalized(&policy);
+                        self.stats.count(&tx.tx_type);
+                        tx
+                    }
+                    Err(e) => {
+                        let e = anyhow::Error::from(e);
+                        if io_error_kind(&e).is_some() {
+                            return Err(e);
+                        }
+                        self.skip(e)?;
+                        continue;
+                    }
+                };
+                if !self.in_tx_id_range(tx.tx_id) {
+                    continue;
+                }
+                if self.config.strict_order {
+                    self.ensure_order(&tx)?;
+                }
+                if tx.tx_type.has_amount() && !self.tx_ids.insert(tx.tx_id) {
+                    self.skip(anyhow!("Duplicate transaction {}", tx.tx_id))?;
+                    continue;
+                }
+                let shard = usize::from(tx.client_id) % workers;
+                batches[shard].push((self.stats.rows, tx));
+                if batches[shard].len() == SHARD_BATCH {
+                    let batch = std::mem::take(&mut batches[shard]);
+                    senders[shard].send(batch).map_err(|_| anyhow!("Worker stopped"))?;
+                }
+            }
+            for (sender, batch) in senders.iter().zip(batches) {
+                sender.send(batch).map_err(|_| anyhow!("Worker stopped"))?;
+            }
+            Ok(())
+        });
+        // clients are taken back even if the input failed, they might be output still
+        self.merge_shards(shards)?;
+        result
+    }
+
+    /// Takes clients and stats of the shards back. A client in more of them would mean
+    /// it was processed by more workers, so its txs might have been applied out of order.
+    fn merge_shards(&mut self, shards: Vec<Shard>) -> anyhow::Result<()> {
+        let mut first_seen = vec![];
+        for shard in shards {
+            self.stats.merge(&shard.stats);
+            first_seen.extend(shard.first_seen);
+            for (client_id, client) in shard.clients {
+                ensure!(
+                    self.clients.insert(client_id, client).is_none(),
+                    "Client {} processed by more than one worker",
+                    client_id
+                );
+            }
+        }
+        first_seen.sort_unstable();
+        self.first_seen.extend(first_seen.into_iter().map(|(_, client_id)| client_id));
+        Ok(())
+    }
+
+    /// Counts the row as skipped, logging the reason only when asked for, for better performance.
+    /// Fails with the reason instead in fail fast mode.
+    fn skip(&mut self, e: anyhow::Error) -> anyhow::Result<()> {
+        if self.config.fail_fast {
+            return Err(e.context(format!("Invalid row {}", self.stats.rows)));
+        }
+        self.stats.skipped += 1;
+        if self.config.verbose {
+            eprintln!("Error: {}", e)
+        }
+        Ok(())
+    }
+
+    /// Records the reason the row is skipped in the audit trail, if there is one.
+    fn audit_rejection(&mut self, row: Option<AuditRow>, e: &anyhow::Error) -> anyhow::Result<()> {
+        if let (Some(audit), Some(row)) = (&mut self.audit, row) {
+            audit.serialize(AuditRow {
+                rejection: Some(format!("{:#}", e)),
+                ..row
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Keeps the rows applied since the batch started if all clients it touched are consistent,
+    /// otherwise restores the state from before the batch and counts its rows as skipped.
+    fn commit_batch(&mut self) -> anyhow::Result<()> {
+        let Some(start) = self.batch_start.take() else {
+            return Ok(());
+        };
+        let touched = start.clients.keys().filter_map(|id| self.clients.get(id));
+        if touched.into_iter().all(|c| c.ensure_consistent().is_ok()) {
+            if let Some(checkpoint) = &mut self.checkpoint {
+                for row in start.checkpoint {
+                    checkpoint.serialize(row)?;
+                }
+            }
+            return Ok(());
+        }
+        for (client_id, client) in start.clients {
+            match client {
+                Some(client) => self.clients.insert(client_id, client),
+                None => self.clients.remove(&client_id),
+            };
+        }
+        for tx_id in &start.tx_ids {
+            self.tx_ids.remove(tx_id);
+        }
+        for tx_id in &start.deposit_ids {
+            self.deposit_ids.remove(tx_id);
+        }
+        // rows of the batch are still read, but all of them are skipped unless checkpointed
+        let rows_in_batch = self.stats.rows - start.stats.rows;
+        let checkpointed_in_batch = self.stats.checkpointed - start.stats.checkpointed;
+        self.stats = Stats {
+            rows: self.stats.rows,
+            skipped: start.stats.skipped + rows_in_batch - checkpointed_in_batch,
+            checkpointed: self.stats.checkpointed,
+            by_type: std::mem::take(&mut self.stats.by_type),
+            ..start.stats
+        };
+        self.first_seen.truncate(start.first_seen);
+        self.ledger.truncate(start.ledger);
+        self.holds.truncate(start.holds);
+        self.pending = start.pending;
+        Ok(())
+    }
+
+    /// Disputes, resolves and chargebacks have to reference a deposit or withdrawal seen earlier
+    /// in the input.
+    fn ensure_order(&mut self, tx: &Tx) -> anyhow::Result<()> {
+        match tx.tx_type {
+            TxType::Deposit { .. } | TxType::Withdrawal { .. } => {
+                if self.deposit_ids.insert(tx.tx_id) {
+                    if let Some(start) = &mut self.batch_start {
+                        start.deposit_ids.push(tx.tx_id);
+                    }
+                }
+            }
+            TxType::Freeze
+            | TxType::Unfreeze
+            | TxType::Delta { .. }
+            | TxType::Adjustment { .. } => {}
+            TxType::Dispute { .. } | TxType::Resolve | TxType::Chargeback => ensure!(
+                self.deposit_ids.contains(&tx.tx_id),
+                "{}({}) precedes its deposit",
+                tx.tx_type.name(),
+                tx.tx_id
+            ),
+        }
+        Ok(())
+    }
+
+    fn process_row<E>(&mut self, row: Result<Tx, E>) -> anyhow::Result<()>
+    where
+        anyhow::Error: From<E>,
+    {
+        let tx = row?;
+        if !self.in_tx_id_range(tx.tx_id) {
+            return Ok(());
+        }
+        if matches!(tx.tx_type, TxType::Deposit { .. } | TxType::Withdrawal { .. }) {
+            ensure!(self.tx_ids.insert(tx.tx_id), "Duplicate transaction {}", tx.tx_id);
+            if let Some(start) = &mut self.batch_start {
+                start.tx_ids.push(tx.tx_id);
+            }
+        }
+        if let Some(max) = self.config.pending_disputes {
+            if tx.tx_type.refers_to_tx() && !self.tx_ids.contains(&tx.tx_id) {
+                ensure!(self.pending.len() < max, "Pending disputes queue is full: {}", max);
+                self.pending.push(tx);
+                return Ok(());
+            }
+        }
+        if let Some(start) = &mut self.batch_start {
+            let client = self.clients.get(&tx.client_id);
+            start.clients.entry(tx.client_id).or_insert_with(|| client.cloned());
+        }
+        let is_new = !self.clients.contains_key(&tx.client_id);
+        let before = self
+            .audit
+            .is_some()
+            .then(|| self.clients.get(&tx.client_id).map(Client::snapshot));
+        let result = apply_tx(&mut self.clients, &mut self.stats, &tx, &self.config.policy);
+        // failed withdrawal might create the client too
+        if is_new && self.clients.contains_key(&tx.client_id) {
+            self.first_seen.push(tx.client_id);
+        }
+        let amount = result?;
+        if let Some(checkpoint) = &mut self.checkpoint {
+            let row = CheckpointRow::new(self.stats.rows, &tx);
+            match &mut self.batch_start {
+                Some(start) => start.checkpoint.push(row),
+                None => checkpoint.serialize(row)?,
+            }
+        }
+        if let (Some(audit), Some(before)) = (&mut self.audit, before) {
+            let after = &self.clients[&tx.client_id];
+            audit.serialize(AuditRow {
+                available_before: Some(before.as_ref().map_or(Decimal::ZERO, |c| c.available)),
+                held_before: Some(before.as_ref().map_or(Decimal::ZERO, |c| c.held)),
+                total_before: Some(before.as_ref().map_or(Decimal::ZERO, |c| c.total)),
+                available_after: Some(after.available),
+                held_after: Some(after.held),
+                total_after: Some(after.total),
+                ..AuditRow::new(&tx)
+            })?;
+        }
+        if tx.tx_type.has_amount() && !self.pending.is_empty() {
+            self.retry_pending(&tx)?;
+        }
+        let affects_balances = !matches!(tx.tx_type, TxType::Freeze | TxType::Unfreeze);
+        if self.config.ledger.is_some() && affects_balances {
+            if let Some(c) = self.clients.get(&tx.client_id) {
+                self.ledger.push(LedgerRow {
+                    client: c.client_id,
+                    tx: tx.tx_id,
+                    operation: tx.tx_type.name(),
+                    delta: amount,
+                    available: c.available,
+                    held: c.held,
+                    total: c.total,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Processes txs which have been waiting for the tx, in order they arrived.
+    fn retry_pending(&mut self, tx: &Tx) -> anyhow::Result<()> {
+        let (ready, pending) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|p| p.tx_id == tx.tx_id);
+        self.pending = pending;
+        for ready in ready {
+            let audit_row = self.audit.is_some().then(|| AuditRow::new(&ready));
+            if let Err(e) = self.process_row(Ok::<_, anyhow::Error>(ready)) {
+                self.audit_rejection(audit_row, &e)?;
+                self.skip(e)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts txs still waiting for their tx at the end of the input as skipped.
+    fn skip_pending(&mut self) -> anyhow::Result<()> {
+        for tx in std::mem::take(&mut self.pending) {
+            let e = anyhow!("Cannot process {:?}({}); Deposit not found", tx.tx_type, tx.tx_id);
+            self.audit_rejection(self.audit.is_some().then(|| AuditRow::new(&tx)), &e)?;
+            self.skip(e)?;
+        }
+        Ok(())
+    }
+
+    /// Merges source accounts into target ones, target account is created if it does not exist.
+    fn merge_accounts(&mut self, merges: &[(u16, u16)]) -> anyhow::Result<()> {
+        for (source, target) in merges {
+            let source = self
+                .clients
+                .remove(source)
+                .ok_or_else(|| anyhow!("Account {} not found", source))?;
+            if !self.clients.contains_key(target) {
+                self.first_seen.push(*target);
+            }
+            self.clients
+                .entry(*target)
+                .or_insert_with(|| Client::create(*target))
+                .merge_from(source)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the action to all deposits still being disputed, locked accounts are skipped.
+    fn drain_disputes(&mut self, action: DrainAction) {
+        for client in self.clients.values_mut() {
+            let withdrawals = client.disputed_withdrawals();
+            if let Ok(amount) = client.drain_disputes(action) {
+                match action {
+                    DrainAction::Chargeback => self.stats.chargedback += amount - withdrawals,
+                    DrainAction::Resolve => self.stats.reversed -= withdrawals,
+                }
+                self.stats.held -= amount;
+            }
+        }
+    }
+
+    /// Settles funds held by deposits still being disputed according to the policy,
+    /// unless it is overridden for the client. Locked accounts are skipped.
+    /// Returns settlements of clients with any funds held, sorted by client id.
+    fn settle(
+        &mut self,
+        policy: SettlePolicy,
+        overrides: &HashMap<u16, SettlePolicy>,
+    ) -> Vec<Settlement> {
+        let mut settlements = vec![];
+        for client in self.clients.values_mut() {
+            if client.held.is_zero() {
+                continue;
+            }
+            let policy = overrides.get(&client.client_id).copied().unwrap_or(policy);
+            let withdrawals = client.disputed_withdrawals();
+            let amount = match policy.action() {
+                Some(action) => match client.drain_disputes(action) {
+                    Ok(amount) => amount,
+                    Err(_) => continue,
+                },
+                None => client.held,
+            };
+            match policy {
+                SettlePolicy::AutoChargeback => self.stats.chargedback += amount - withdrawals,
+                SettlePolicy::Hold => {}
+                SettlePolicy::AutoResolve => self.stats.reversed -= withdrawals,
+            }
+            if policy != SettlePolicy::Hold {
+                self.stats.held -= amount;
+            }
+            settlements.push(Settlement {
+                client_id: client.client_id,
+                policy,
+                amount,
+            });
+        }
+        settlements.sort_unstable_by_key(|s| s.client_id);
+        settlements
+    }
+
+    pub(crate) fn summary(&self) -> RunSummary {
+        RunSummary {
+            rows: self.stats.rows,
+            errors: self.stats.skipped,
+            checkpointed: self.stats.checkpointed,
+            clients: self.clients.len(),
+            locked: self.clients.values().filter(|c| c.locked_or_frozen()).count(),
+            deposited: self.stats.deposited,
+            withdrawn: self.stats.withdrawn,
+            chargedback: self.stats.chargedback,
+            held: self.stats.held,
+        }
+    }
+
+    /// Counts of rows read, of them by tx type, of skipped rows and of locked accounts.
+    pub(crate) fn stats_line(&self) -> String {
+        let mut line = format!("rows {}", self.stats.rows);
+        for tx_type in TxType::all() {
+            let count = self.stats.by_type.get(tx_type.name()).copied().unwrap_or(0);
+            if count > 0 || tx_type.required_flag().is_none() {
+                line += &format!(", {} {}", tx_type.name(), count);
+            }
+        }
+        let locked = self.clients.values().filter(|c| c.locked_or_frozen()).count();
+        line + &format!(", skipped {}, locked {}", self.stats.skipped, locked)
+    }
+
+    /// Loads clients from the configured seed, unless they have been loaded already,
+    /// e.g. by an earlier processing since the last [`Engine::reset`].
+    fn seed_once(&mut self) -> anyhow::Result<()> {
+        if let (Some(path), false) = (self.config.seed.clone(), self.seeded) {
+            self.seed(&path).with_context(|| format!("Invalid seed {}", path.display()))?;
+            self.seeded = true;
+        }
+        Ok(())
+    }
+
+    /// Loads clients with their funds from the output CSV of a previous run.
+    /// Their deposits are not known, so those cannot be disputed.
+    fn seed(&mut self, path: &Path) -> anyhow::Result<()> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)?;
+        for row in rdr.deserialize() {
+            let row: SeedRow = row?;
+            ensure!(
+                !self.clients.contains_key(&row.client),
+                "Client {} seeded more than once",
+                row.client
+            );
+            let mut client = Client::with_balance(row.client, row.available, row.held, row.total)?;
+            client.locked = row.locked;
+            self.first_seen.push(row.client);
+            self.clients.insert(row.client, client);
+        }
+        Ok(())
+    }
+
+    fn sum_of_totals(&self) -> Decimal {
+        self.clients.values().map(|c| c.total).sum()
+    }
+
+    /// Checks that the change of clients' totals since the opening
+    /// is explained by the processed deposits, withdrawals and chargebacks.
+    fn ensure_balanced(&self, opening_total: Decimal) -> anyhow::Result<()> {
+        let change = self.sum_of_totals() - opening_total;
+        ensure!(
+            change == self.stats.net(),
+            "Ledger is not balanced: change of totals {} != {} deposited - {} withdrawn - {} charged back",
+            change,
+            self.stats.deposited,
+            self.stats.withdrawn,
+            self.stats.chargedback,
+        );
+        Ok(())
+    }
+
+    /// Checks that no client's total is below the floor or above the ceiling.
+    fn ensure_within_bounds(&self) -> anyhow::Result<()> {
+        let floor = self.config.balance_floor;
+        let ceiling = self.config.balance_ceiling;
+        let mut offending: Vec<&Client> = self
+            .clients
+            .values()
+            .filter(|c| floor.is_some_and(|f| c.total < f) || ceiling.is_some_and(|f| c.total > f))
+            .collect();
+        offending.sort_unstable_by_key(|c| c.client_id);
+        ensure!(
+            offending.is_empty(),
+            "Clients with total out of bounds: {}",
+            offending
+                .iter()
+                .map(|c| format!("{} ({})", c.client_id, c.total))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        Ok(())
+    }
+
+    fn in_tx_id_range(&self, tx_id: u32) -> bool {
+        self.config.min_tx_id.is_none_or(|min| tx_id >= min)
+            && self.config.max_tx_id.is_none_or(|max| tx_id <= max)
+    }
+
+    fn output(&self) -> anyhow::Result<()> {
+        if let Some(path) = &self.config.output {
+            let file = File::create(path)
+                .with_context(|| format!("Cannot create output {}", path.display()))?;
+            return self.write_output(io::BufWriter::new(file));
+        }
+        match self.write_output(std::io::stdout()) {
+            // reader of the output went away (e.g. `| head`), nothing more to do
+            Err(e) if is_broken_pipe(&e) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn write_output<W: Write>(&self, mut writer: W) -> anyhow::Result<()> {
+        if self.config.json_map {
+            return self.write_json_map(writer);
+        }
+        if self.config.output_format == OutputFormat::Json {
+            return self.write_json(writer);
+        }
+        #[cfg(feature = "proto")]
+        if self.config.output_format == OutputFormat::Proto {
+            return self.write_proto(writer);
+        }
+        if self.config.pretty {
+            let mut csv = vec![];
+            self.write_csv(&mut csv)?;
+            return output::write_table(csv.as_slice(), writer);
+        }
+        if self.config.view.raw_amounts {
+            writeln!(writer, "# amounts are scaled by {}", amount::SCALE)?;
+        }
+        self.write_csv(writer)
+    }
+
+    /// Clients in the configured order.
+    fn clients_in_output_order(&self) -> Vec<&Client> {
+        let mut clients = self.clients_snapshot_sorted(self.config.sort);
+        clients.retain(|c| self.is_shown(c));
+        clients
+    }
+
+    fn is_shown(&self, client: &Client) -> bool {
+        !(self.config.hide_empty && client.is_empty())
+    }
+
+    fn write_csv<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(self.config.output_delimiter())
+            .from_writer(writer);
+        for (i, c) in self.clients_in_output_order().into_iter().enumerate() {
+            wtr.serialize(c.view(self.config.view))?;
+            if self.config.flush_every.is_some_and(|n| (i + 1).is_multiple_of(n)) {
+                wtr.flush()?;
+            }
+        }
+
+        Ok(wtr.flush()?)
+    }
+
+    /// Writes JSON array of clients.
+    fn write_json<W: Write>(&self, mut writer: W) -> anyhow::Result<()> {
+        let views: Vec<ClientView> = self
+            .clients_in_output_order()
+            .into_iter()
+            .map(|c| c.view(self.config.view))
+            .collect();
+        serde_json::to_writer(&mut writer, &views)?;
+        writeln!(writer)?;
+        Ok(writer.flush()?)
+    }
+
+    /// Writes length-delimited protobuf messages of clients.
+    #[cfg(feature = "proto")]
+    fn write_proto<W: Write>(&self, mut writer: W) -> anyhow::Result<()> {
+        use prost::Message;
+        for c in self.clients_in_output_order() {
+            writer.write_all(&c.to_proto()?.encode_length_delimited_to_vec())?;
+        }
+        Ok(writer.flush()?)
+    }
+
+    /// Writes JSON object with client ids as sorted keys.
+    fn write_json_map<W: Write>(&self, mut writer: W) -> anyhow::Result<()> {
+        let options = ViewOptions {
+            without_id: true,
+            ..self.config.view
+        };
+        let map: BTreeMap<u16, ClientView> = self
+            .clients
+            .iter()
+            .filter(|(_, c)| self.is_shown(c))
+            .map(|(id, c)| (*id, c.view(options)))
+            .collect();
+        serde_json::to_writer(&mut writer, &map)?;
+        writeln!(writer)?;
+        Ok(writer.flush()?)
+    }
+
+    /// Writes total held funds after every `holds_interval` rows.
+    fn write_holds<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        wtr.write_record(["row", "total_held"])?;
+        for (row, held) in &self.holds {
+            wtr.serialize((row, held))?;
+        }
+        Ok(wtr.flush()?)
+    }
+
+    /// Writes every applied operation in order of the input.
+    fn write_ledger<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        for row in &self.ledger {
+            wtr.serialize(row)?;
+        }
+        Ok(wtr.flush()?)
+    }
+
+    /// Writes every deposit with its state and number of disputes, sorted by client and tx id.
+    fn write_deposits<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        for c in self.clients_snapshot_sorted(SortKey::Id) {
+            for row in c.deposit_rows() {
+                wtr.serialize(row)?;
+            }
+        }
+        Ok(wtr.flush()?)
+    }
+
+    /// Writes every tx still being disputed, sorted by client and tx id.
+    fn write_disputes<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        wtr.write_record(["client", "tx"])?;
+        for c in self.clients_snapshot_sorted(SortKey::Id) {
+            for tx_id in c.disputed_tx_ids() {
+                wtr.serialize((c.client_id, tx_id))?;
+            }
+        }
+        Ok(wtr.flush()?)
+    }
+
+    /// Writes final state of each client as JSON line, versioned by the number of applied txs,
+    /// so downstream might upsert them idempotently.
+    fn write_cdc<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        let mut writer = io::BufWriter::new(writer);
+        let options = ViewOptions {
+            version: true,
+            ..Default::default()
+        };
+        for c in self.clients_snapshot_sorted(SortKey::Id) {
+            serde_json::to_writer(&mut writer, &c.view(options))?;
+            writeln!(writer)?;
+        }
+        Ok(writer.flush()?)
+    }
+
+    /// Clients sorted ascending by the key, ties are broken by client id,
+    /// so the order is fully deterministic, unless they are unsorted.
+    pub(crate) fn clients_snapshot_sorted(&self, key: SortKey) -> Vec<&Client> {
+        let mut clients: Vec<&Client> = self.clients.values().collect();
+        match key {
+            SortKey::Id => clients.sort_unstable_by_key(|c| c.client_id),
+            SortKey::Total => clients.sort_unstable_by_key(|c| (c.total, c.client_id)),
+            SortKey::Available => clients.sort_unstable_by_key(|c| (c.available, c.client_id)),
+            SortKey::FirstSeen => {
+                let mut positions = HashMap::new();
+                for (i, id) in self.first_seen.iter().enumerate() {
+                    positions.entry(*id).or_insert(i);
+                }
+                clients.sort_unstable_by_key(|c| {
+                    (positions.get(&c.client_id).copied().unwrap_or(usize::MAX), c.client_id)
+                })
+            }
+            SortKey::Unsorted => {}
+        }
+        clients
+    }
+}
+
+/// Applies the tx to the client it belongs to and records it in the stats.
+/// Returns the amount affected by the tx.
+fn apply_tx(
+    clients: &mut HashMap<u16, Client>,
+    stats: &mut Stats,
+    tx: &Tx,
+    policy: &Policy,
+) -> anyhow::Result<Decimal> {
+    let amount = tx
+        .process(clients, policy)
+        .map_err(|e| anyhow!("Cannot process {:?}({}); {}", tx.tx_type, tx.tx_id, e))?;
+    // dispute by amount refers only to deposits
+    let by_amount =
+        policy.dispute_by_amount && matches!(tx.tx_type, TxType::Dispute { amount: Some(_) });
+    let of_withdrawal = !by_amount && tx.tx_type.refers_to_tx();
+    match clients.get(&tx.client_id) {
+        Some(client) if of_withdrawal && client.is_withdrawal(&tx.tx_id) => {
+            stats.record_withdrawal_dispute(&tx.tx_type, amount)
+        }
+        _ => stats.record(&tx.tx_type, amount),
+    }
+    Ok(amount)
+}
+
+/// Client as written to the output CSV, other columns are ignored.
+#[derive(Deserialize)]
+struct SeedRow {
+    client: u16,
+    #[serde(deserialize_with = "amount::deserialize")]
+    available: Decimal,
+    #[serde(deserialize_with = "amount::deserialize")]
+    held: Decimal,
+    #[serde(deserialize_with = "amount::deserialize")]
+    total: Decimal,
+    locked: bool,
+}
+
+/// Reads (source, target) client ids from CSV with `source` and `target` columns.
+fn read_merges(path: &Path) -> anyhow::Result<Vec<(u16, u16)>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)?;
+    Ok(rdr.deserialize().collect::<Result<_, _>>()?)
+}
+
+fn is_broken_pipe(e: &anyhow::Error) -> bool {
+    io_error_kind(e) == Some(io::ErrorKind::BrokenPipe)
+}
+
+/// Kind of the IO error behind the error, if there is any.
+fn io_error_kind(e: &anyhow::Error) -> Option<io::ErrorKind> {
+    if let Some(e) = e.downcast_ref::<csv::Error>() {
+        return match e.kind() {
+            csv::ErrorKind::Io(e) => Some(e.kind()),
+            _ => None,
+        };
+    }
+    if let Some(e) = e.downcast_ref::<serde_json::Error>() {
+        return e.io_error_kind();
+    }
+    e.downcast_ref::<io::Error>().map(|e| e.kind())
+}
+
+#[cfg(test)]
+#[allow(clippy::approx_constant)]
+mod tests {
+    use super::*;
+    use rust_decimal::{Decimal, prelude::FromPrimitive};
+    use rand::{thread_rng, Rng};
+    use crate::gen::Generator;
+
+    fn random() -> Decimal {
+        let r: Decimal = thread_rng().gen_range(1..1_000_000_000).into();
+        r / Decimal::from(10_000)
+    }
+
+    fn assert_example_result(engine: &mut Engine) {
+        let client = engine.clients.get(&1).unwrap();
+        assert_eq!(client.available, Decimal::from_f32(1.5).unwrap());
+        assert_eq!(client.held, 0.into());
+        assert_eq!(client.total, Decimal::from_f32(1.5).unwrap());
+        let client = engine.clients.get(&2).unwrap();
+        assert_eq!(client.available, 2.into());
+        assert_eq!(client.held, 0.into());
+        assert_eq!(client.total, 2.into());
+    }
+
+    fn fast_parse_engine() -> Engine {
+        Engine::new(Config {
+            fast_parse: true,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn should_handle_example() -> anyhow::Result<()> {
+        let mut engine = Engine::default();
+        engine.process_file("test_samples/example.csv".into())?;
+        assert_example_result(&mut engine);
+        Ok(())
+    }
+
+    #[test]
+    fn should_handle_spaceless_format() -> anyhow::Result<()> {
+        let mut engine = Engine::default();
+        engine.process_file("test_samples/spaceless.csv".into())?;
+        assert_example_result(&mut engine);
+        Ok(())
+    }
+
+    #[test]
+    fn should_handle_spacefull_format() -> anyhow::Result<()> {
+        let mut engine = Engine::default();
+        engine.process_file("test_samples/spacefull.csv".into())?;
+        assert_example_result(&mut engine);
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_bad_header() {
+        for mut engine in [Engine::default(), fast_parse_engine()] {
+            let err = engine.process_file("test_samples/bad_header.csv".into()).unwrap_err();
+            assert_eq!(
+                format!("{:#}", err),
+                "Missing columns amount in the header type,client,tx,ammount"
+            );
+        }
+    }
+
+    #[test]
+    fn should_handle_bom_and_semicolon_delimiter() -> anyhow::Result<()> {
+        for fast_parse in [false, true] {
+            let mut engine = Engine::new(Config {
+                fast_parse,
+                ..Default::default()
+            })?;
+            engine.process_file("test_samples/bom.csv".into())?;
+            assert_example_result(&mut engine);
+
+            let mut engine = Engine::new(Config {
+                fast_parse,
+                delimiter: Some(b';'),
+                ..Default::default()
+            })?;
+            engine.process_file("test_samples/semicolon.csv".into())?;
+            assert_example_result(&mut engine);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_read_and_write_tsv() -> anyhow::Result<()> {
+        for fast_parse in [false, true] {
+            let mut engine = Engine::new(Config {
+                fast_parse,
+                tsv: true,
+                sort: SortKey::Id,
+                ..Default::default()
+            })?;
+            engine.process_file("test_samples/example.tsv".into())?;
+            assert_example_result(&mut engine);
+            let mut output = vec![];
+            engine.write_output(&mut output)?;
+            assert_eq!(
+                String::from_utf8(output.clone())?,
+                "client\tavailable\theld\ttotal\tlocked\n\
+                 1\t1.5\t0.0\t1.5\tfalse\n\
+                 2\t2.0\t0.0\t2.0\tfalse\n"
+            );
+            let rows: Vec<SeedRow> = csv::ReaderBuilder::new()
+                .delimiter(b'\t')
+                .from_reader(output.as_slice())
+                .deserialize()
+                .collect::<Result<_, _>>()?;
+            for row in rows {
+                let client = &engine.clients[&row.client];
+                assert_eq!(row.available, client.available);
+                assert_eq!(row.held, client.held);
+                assert_eq!(row.total, client.total);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_handle_json_and_ndjson_formats() -> anyhow::Result<()> {
+        for (format, file) in [
+            (InputFormat::Json, "test_samples/example.json"),
+            (InputFormat::Ndjson, "test_samples/example.ndjson"),
+        ] {
+            let mut engine = Engine::new(Config {
+                input_format: format,
+                ..Default::default()
+            })?;
+            engine.process_file(file.into())?;
+            assert_example_result(&mut engine);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_skip_wrong_elements_of_json_array() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            input_format: InputFormat::Json,
+            ..Default::default()
+        })?;
+        engine.process_file("test_samples/wrong.json".into())?;
+        let client = engine.clients.get(&1).unwrap();
+        assert_eq!(client.available, 1.into());
+        assert_eq!(client.total, 1.into());
+        let client = engine.clients.get(&2).unwrap();
+        assert_eq!(client.available, 2.into());
+        assert_eq!(client.total, 2.into());
+        Ok(())
+    }
+
+    #[test]
+    fn should_detect_input_format_in_auto_mode() -> anyhow::Result<()> {
+        for file in [
+            "test_samples/example.csv",
+            "test_samples/example.json",
+            "test_samples/example.ndjson",
+        ] {
+            let mut engine = Engine::new(Config {
+                input_format: InputFormat::Auto,
+                ..Default::default()
+            })?;
+            engine.process_file(file.into())?;
+            assert_example_result(&mut engine);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_ignore_txs_outside_of_tx_id_range() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            min_tx_id: Some(2),
+            max_tx_id: Some(4),
+            ..Default::default()
+        })?;
+        engine.process_file("test_samples/example.csv".into())?;
+        let client = engine.clients.get(&1).unwrap();
+        assert_eq!(client.available, Decimal::from_f32(0.5).unwrap());
+        assert_eq!(client.total, Decimal::from_f32(0.5).unwrap());
+        let client = engine.clients.get(&2).unwrap();
+        assert_eq!(client.available, 2.into());
+        assert_eq!(client.total, 2.into());
+        Ok(())
+    }
+
+    #[test]
+    fn should_handle_fractions_only_when_allowed() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            allow_fractions: true,
+            ..Default::default()
+        })?;
+        engine.process_file("test_samples/fractions.csv".into())?;
+        assert_eq!(
+            engine.clients.get(&1).unwrap().total,
+            Decimal::from_f32(0.3333).unwrap()
+        );
+        assert_eq!(engine.clients.get(&2).unwrap().total, 2.into());
+
+        let mut engine = Engine::default();
+        engine.process_file("test_samples/fractions.csv".into())?;
+        assert!(!engine.clients.contains_key(&1));
+        assert_eq!(
+            engine.clients.get(&2).unwrap().total,
+            Decimal::from_f32(1.5).unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_abort_on_dispute_preceding_deposit_in_strict_order() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            strict_order: true,
+            ..Default::default()
+        })?;
+        assert_eq!(
+            engine
+                .process_file("test_samples/early_dispute.csv".into())
+                .unwrap_err()
+                .to_string(),
+            "dispute(3) precedes its deposit"
+        );
+
+        let mut engine = Engine::default();
+        engine.process_file("test_samples/early_dispute.csv".into())?;
+        let client = engine.clients.get(&1).unwrap();
+        assert_eq!(client.available, 2.into());
+        assert_eq!(client.held, 1.into());
+        Ok(())
+    }
+
+    #[test]
+    fn should_drain_open_disputes() -> anyhow::Result<()> {
+        for (action, available, total) in [
+            (DrainAction::Resolve, 4.28, 4.28),
+            (DrainAction::Chargeback, 1.14, 1.14),
+        ] {
+            let mut engine = Engine::default();
+            engine.process_file("test_samples/nonexistent.csv".into())?;
+            engine.drain_disputes(action);
+            let client = engine.clients.get(&3).unwrap();
+            assert_eq!(client.available, Decimal::from_f32(available).unwrap());
+            assert_eq!(client.held, 0.into());
+            assert_eq!(client.total, Decimal::from_f32(total).unwrap());
+            engine.ensure_balanced(0.into())?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_handle_all_formats_with_fast_parse() -> anyhow::Result<()> {
+        for file in [
+            "test_samples/example.csv",
+            "test_samples/spaceless.csv",
+            "test_samples/spacefull.csv",
+        ] {
+            let mut engine = fast_parse_engine();
+            engine.process_file(file.into())?;
+            assert_example_result(&mut engine);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_process_same_with_fast_parse() -> anyhow::Result<()> {
+        for file in ["test_samples/wrong.csv", "test_samples/nonexistent.csv"] {
+            let mut engine = Engine::default();
+            engine.process_file(file.into())?;
+            let mut fast = fast_parse_engine();
+            fast.process_file(file.into())?;
+            assert_eq!(fast.clients.len(), engine.clients.len());
+            for (id, client) in &engine.clients {
+                let fast_client = fast.clients.get(id).unwrap();
+                assert_eq!(fast_client.available, client.available);
+                assert_eq!(fast_client.held, client.held);
+                assert_eq!(fast_client.total, client.total);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_handle_admin_freeze_only_when_allowed() -> anyhow::Result<()> {
+        for (allow_admin_freeze, available) in [(true, 3), (false, 2)] {
+            let mut engine = Engine::new(Config {
+                policy: Policy {
+                    allow_admin_freeze,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })?;
+            engine.process_file("test_samples/freeze.csv".into())?;
+            let client = engine.clients.get(&1).unwrap();
+            assert_eq!(client.available, available.into());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_verify_input_checksum() -> anyhow::Result<()> {
+        let checksum = "4e3926a91b4a48e56f1bd7bb1fb4124c9b5154a2be9b55149678bbb9938ddde6";
+        let mut engine = Engine::new(Config {
+            checksum: Some(checksum.to_uppercase()),
+            ..Default::default()
+        })?;
+        engine.process_file("test_samples/example.csv".into())?;
+        assert_example_result(&mut engine);
+
+        let mut engine = Engine::new(Config {
+            checksum: Some(checksum.replace('4', "5")),
+            ..Default::default()
+        })?;
+        assert_eq!(
+            engine
+                .process_file("test_samples/example.csv".into())
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "Checksum mismatch for test_samples/example.csv: expected {}, got {}",
+                checksum.replace('4', "5"),
+                checksum
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_create_account_on_withdrawal_when_configured() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            policy: Policy {
+                create_on_withdrawal: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        })?;
+        engine.process_file("test_samples/withdrawal_only.csv".into())?;
+        let client = engine.clients.get(&2).unwrap();
+        assert_eq!(client.available, 0.into());
+        assert_eq!(client.held, 0.into());
+        assert_eq!(client.total, 0.into());
+
+        let mut engine = Engine::default();
+        engine.process_file("test_samples/withdrawal_only.csv".into())?;
+        assert!(!engine.clients.contains_key(&2));
+        Ok(())
+    }
+
+    #[test]
+    fn should_skip_too_long_lines() -> anyhow::Result<()> {
+        let input = format!(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 1.0\n\
+             deposit, 1, 2, 1{}\n\
+             deposit, 2, 3, 2.0\n",
+            "0".repeat(1_000_000)
+        );
+        for (input_format, fast_parse) in [
+            (InputFormat::Csv, false),
+            (InputFormat::Csv, true),
+            (InputFormat::Auto, false),
+        ] {
+            let config = || Config {
+                input_format,
+                fast_parse,
+                max_line_length: Some(1024),
+                ..Default::default()
+            };
+            let mut engine = Engine::new(config())?;
+            engine.process_reader(input.as_bytes())?;
+            assert_eq!(engine.clients.get(&1).unwrap().total, 1.into());
+            assert_eq!(engine.clients.get(&2).unwrap().total, 2.into());
+            assert_eq!((engine.stats.rows, engine.stats.skipped), (3, 1));
+
+            let mut engine = Engine::new(Config {
+                fail_fast: true,
+                ..config()
+            })?;
+            let e = engine.process_reader(input.as_bytes()).unwrap_err();
+            assert!(e.to_string().starts_with("Invalid row 2"), "{:#}", e);
+        }
+        let ndjson = format!(
+            "{{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 1{}}}\n\
+             {{\"type\": \"deposit\", \"client\": 2, \"tx\": 2, \"amount\": 2.0}}\n",
+            "0".repeat(2048)
+        );
+        let mut engine = Engine::new(Config {
+            input_format: InputFormat::Ndjson,
+            max_line_length: Some(1024),
+            ..Default::default()
+        })?;
+        engine.process_reader(ndjson.as_bytes())?;
+        assert_eq!((engine.stats.rows, engine.stats.skipped), (2, 1));
+        // too long header would make the next row one
+        let mut engine = Engine::new(Config {
+            max_line_length: Some(10),
+            ..Default::default()
+        })?;
+        assert!(engine.process_reader("type,client,tx,amount\n".as_bytes()).is_err());
+        // lines are limited only on request
+        let padded = format!("type,client,tx,amount\ndeposit,1,1,1.0{}\n", " ".repeat(2048));
+        let mut engine = Engine::default();
+        engine.process_reader(padded.as_bytes())?;
+        assert_eq!(engine.clients.get(&1).unwrap().total, 1.into());
+        Ok(())
+    }
+
+    #[test]
+    fn should_apply_deltas_with_both_parsers() -> anyhow::Result<()> {
+        let input = "type,client,tx,amount,available_delta,held_delta,total_delta\n\
+                     deposit,1,1,5.0,,,\n\
+                     delta,1,2,,-1.5,1.5,0\n\
+                     delta,1,3,,1,,1\n";
+        for fast_parse in [false, true] {
+            let mut engine = Engine::new(Config {
+                fast_parse,
+                fail_fast: true,
+                policy: Policy {
+                    allow_delta: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })?;
+            let e = engine.process_reader(input.as_bytes()).unwrap_err();
+            assert!(format!("{:#}", e).ends_with("Delta row 3 missing held_delta"), "{:#}", e);
+            let client = engine.clients.get(&1).unwrap();
+            assert_eq!((client.available, client.held), (Decimal::new(35, 1), Decimal::new(15, 1)));
+            assert_eq!(client.total, 5.into());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_rows_missing_amount() -> anyhow::Result<()> {
+        for fast_parse in [false, true] {
+            let config = || Config {
+                fast_parse,
+                ..Default::default()
+            };
+            let mut engine = Engine::new(config())?;
+            engine.process_file("test_samples/missing_amount.csv".into())?;
+            assert_eq!(engine.stats.deposited, 2.into());
+            assert_eq!(engine.stats.withdrawn, 1.into());
+            // the dispute is skipped too, as deposit 1 is not available anymore
+            assert_eq!((engine.stats.rows, engine.stats.skipped), (5, 3));
+
+            let mut engine = Engine::new(Config {
+                fail_fast: true,
+                ..config()
+            })?;
+            let e = engine.process_file("test_samples/missing_amount.csv".into()).unwrap_err();
+            assert_eq!(e.to_string(), "Invalid row 2");
+            assert!(format!("{:#}", e).ends_with("Deposit row 2 missing amount"), "{:#}", e);
+
+            // trailing fields might be left out, but not added
+            let input = "type,client,tx,amount\ndeposit,1,1,2.0\ndispute,1,1\nwithdrawal,1,4\n";
+            let mut engine = Engine::new(Config {
+                fail_fast: true,
+                ..config()
+            })?;
+            let e = engine.process_reader(input.as_bytes()).unwrap_err();
+            assert_eq!(e.to_string(), "Invalid row 3");
+            assert!(format!("{:#}", e).ends_with("Withdrawal row 4 missing amount"), "{:#}", e);
+            assert_eq!(engine.clients.get(&1).unwrap().held, 2.into());
+            let mut engine = Engine::new(config())?;
+            engine.process_reader("type,client,tx,amount\ndeposit,1,1,2.0,3\n".as_bytes())?;
+            assert_eq!(engine.stats.skipped, 1);
+        }
+        let mut engine = Engine::new(Config {
+            input_format: InputFormat::Ndjson,
+            fail_fast: true,
+            ..Default::default()
+        })?;
+        let e = engine
+            .process_reader(r#"{"type": "withdrawal", "client": 1, "tx": 3}"#.as_bytes())
+            .unwrap_err();
+        assert!(format!("{:#}", e).contains("Withdrawal row 3 missing amount"), "{:#}", e);
+        Ok(())
+    }
+
+    #[test]
+    fn should_settle_held_funds_according_to_policy() -> anyhow::Result<()> {
+        for (policy, available, held, total) in [
+            (SettlePolicy::AutoResolve, 4.28, 0., 4.28),
+            (SettlePolicy::AutoChargeback, 1.14, 0., 1.14),
+            (SettlePolicy::Hold, 1.14, 3.14, 4.28),
+        ] {
+            let mut engine = Engine::default();
+            engine.process_file("test_samples/nonexistent.csv".into())?;
+            assert_eq!(
+                engine.settle(policy, &HashMap::new()),
+                vec![Settlement {
+                    client_id: 3,
+                    policy,
+                    amount: Decimal::from_f32(3.14).unwrap(),
+                }]
+            );
+            let client = engine.clients.get(&3).unwrap();
+            assert_eq!(client.available, Decimal::from_f32(available).unwrap());
+            assert_eq!(client.held, Decimal::from_f32(held).unwrap());
+            assert_eq!(client.total, Decimal::from_f32(total).unwrap());
+            engine.ensure_balanced(0.into())?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_settle_with_per_client_override() -> anyhow::Result<()> {
+        let mut engine = Engine::default();
+        engine.process_file("test_samples/nonexistent.csv".into())?;
+        let overrides = settle::read_overrides("test_samples/settle_overrides.csv".as_ref())?;
+        let settlements = engine.settle(SettlePolicy::AutoChargeback, &overrides);
+        assert_eq!(settlements[0].policy, SettlePolicy::Hold);
+        let client = engine.clients.get(&3).unwrap();
+        assert_eq!(client.held, Decimal::from_f32(3.14).unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn should_skip_wrong_lines_in_csv_but_process_rest() -> anyhow::Result<()> {
+        let mut engine = Engine::default();
+        engine.process_file("test_samples/wrong.csv".into())?;
+        let client = engine.clients.get(&1).unwrap();
+        assert_eq!(client.available, 1.into());
+        assert_eq!(client.held, 0.into());
+        assert_eq!(client.total, 1.into());
+        let client = engine.clients.get(&2).unwrap();
+        assert_eq!(client.available, 2.into());
+        assert_eq!(client.held, 0.into());
+        assert_eq!(client.total, 2.into());
+        Ok(())
+    }
+
+    #[test]
+    fn should_skip_row_with_unterminated_quote() -> anyhow::Result<()> {
+        for fast_parse in [false, true] {
+            let mut engine = Engine::new(Config {
+                fast_parse,
+                ..Default::default()
+            })?;
+            engine.process_file("test_samples/unterminated_quote.csv".into())?;
+            assert_example_result(&mut engine);
+            assert_eq!(engine.stats.skipped, 2);
+        }
+        Ok(())
+    }
+
+    struct FailingReader(usize);
+
+    impl io::Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\n".as_bytes();
+            if self.0 >= input.len() {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            let n = buf.len().min(input.len() - self.0);
+            buf[..n].copy_from_slice(&input[self.0..self.0 + n]);
+            self.0 += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn should_abort_on_io_error_while_reading() {
+        for (input_format, fast_parse) in [
+            (InputFormat::Csv, false),
+            (InputFormat::Csv, true),
+            (InputFormat::Ndjson, false),
+        ] {
+            let mut engine = Engine::new(Config {
+                input_format,
+                fast_parse,
+                ..Default::default()
+            })
+            .unwrap();
+            let e = engine
+                .process_reader(BufReader::new(FailingReader(0)))
+                .unwrap_err();
+            assert_eq!(io_error_kind(&e), Some(io::ErrorKind::ConnectionReset));
+        }
+    }
+
+    #[test]
+    fn should_skip_nonexistent_accounts() -> anyhow::Result<()> {
+        let mut engine = Engine::default();
+        engine.process_file("test_samples/nonexistent.csv".into())?;
+        let client = engine.clients.get(&1).unwrap();
+        assert_eq!(client.available, Decimal::from_f32(0.49).unwrap());
+        assert_eq!(client.held, 0.into());
+        assert_eq!(client.total, Decimal::from_f32(0.49).unwrap());
+        let client = engine.clients.get(&2).unwrap();
+        assert_eq!(client.available, Decimal::from_f32(0.).unwrap());
+        assert_eq!(client.held, Decimal::from_f32(0.).unwrap());
+        assert_eq!(client.total, Decimal::from_f32(0.).unwrap());
+        let client = engine.clients.get(&3).unwrap();
+        assert_eq!(client.available, Decimal::from_f32(1.14).unwrap());
+        assert_eq!(client.held, Decimal::from_f32(3.14).unwrap());
+        assert_eq!(client.total, Decimal::from_f32(4.28).unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn should_check_if_ledger_is_balanced() -> anyhow::Result<()> {
+        let mut engine = Engine::default();
+        engine.process_file("test_samples/nonexistent.csv".into())?;
+        engine.ensure_balanced(0.into())?;
+
+        engine.clients.get_mut(&3).unwrap().total += Decimal::from(1);
+        assert_eq!(
+            engine.ensure_balanced(0.into()).unwrap_err().to_string(),
+            "Ledger is not balanced: change of totals 5.77 != 9.38 deposited - 2.61 withdrawn - 2 charged back"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_balance_disputes_of_withdrawals() -> anyhow::Result<()> {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,5.0\nwithdrawal,1,2,2.0\ndispute,1,2,\nresolve,1,2,\n\
+            deposit,2,3,5.0\nwithdrawal,2,4,2.0\ndispute,2,4,\nchargeback,2,4,\n\
+            deposit,3,5,5.0\nwithdrawal,3,6,2.0\ndispute,3,6,\nwithdrawal,2,7,1.0\n";
+        let mut engine = Engine::new(Config {
+            policy: Policy {
+                allow_withdrawal_disputes: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        })?;
+        engine.process_input("stdin", input.as_bytes())?;
+        assert_eq!(engine.clients[&1].total, 3.into());
+        // the returned withdrawal does not lock the account
+        assert!(!engine.clients[&2].locked);
+        assert_eq!(engine.clients[&2].total, 4.into());
+        assert_eq!(engine.clients[&2].available, 4.into());
+        assert_eq!(engine.clients[&3].held, 2.into());
+        engine.ensure_balanced(0.into())?;
+        engine.drain_disputes(DrainAction::Resolve);
+        assert_eq!(engine.clients[&3].total, 3.into());
+        engine.ensure_balanced(0.into())?;
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_negative_amounts_unless_signed() -> anyhow::Result<()> {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 5.0\n\
+                     deposit, 1, 2, -2.0\n\
+                     withdrawal, 1, 3, -1.5\n";
+        let mut engine = Engine::default();
+        engine.process_reader(input.as_bytes())?;
+        assert_eq!(engine.clients[&1].total, 5.into());
+        assert_eq!(engine.stats.skipped, 2);
+
+        let mut engine = Engine::new(Config {
+            policy: Policy {
+                signed_amounts: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        })?;
+        engine.process_reader(input.as_bytes())?;
+        assert_eq!(engine.clients[&1].total, Decimal::new(45, 1));
+        assert_eq!(engine.stats.skipped, 0);
+        assert_eq!(engine.stats.deposited, Decimal::new(65, 1));
+        assert_eq!(engine.stats.withdrawn, 2.into());
+        Ok(())
+    }
+
+    #[test]
+    fn should_abort_on_first_invalid_row_when_failing_fast() -> anyhow::Result<()> {
+        let mut engine = Engine::default();
+        engine.process_file("test_samples/wrong.csv".into())?;
+        assert_eq!(engine.stats.skipped, 3);
+
+        let mut engine = Engine::new(Config {
+            fail_fast: true,
+            ..Default::default()
+        })?;
+        let e = engine.process_file("test_samples/wrong.csv".into()).unwrap_err();
+        assert_eq!(e.to_string(), "Invalid row 3");
+        assert!(format!("{:#}", e).contains("Unknown tx type dposit"));
+        assert_eq!(engine.stats.rows, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn should_fail_check_of_invalid_rows() -> anyhow::Result<()> {
+        // withdrawal exceeding available funds cannot be applied
+        let e = Engine::default().check(vec!["test_samples/example.csv".into()]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Found 1 invalid rows of 5"
+        );
+        let path = std::env::temp_dir()
+            .join(format!("tx_fun_check_checkpoint_{}.csv", std::process::id()));
+        let engine = Engine::new(Config {
+            checkpoint: Some(path.clone()),
+            ..Default::default()
+        })?;
+        assert_eq!(
+            engine.check(vec!["test_samples/wrong.csv".into()]).unwrap_err().to_string(),
+            "Found 3 invalid rows of 5"
+        );
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn should_write_audit_trail() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join("tx_fun_audit.csv");
+        let mut engine = Engine::new(Config {
+            audit: Some(path.clone()),
+            ..Default::default()
+        })?;
+        engine.process(vec!["test_samples/example.csv".into()])?;
+        assert_example_result(&mut engine);
+        drop(engine);
+        assert_eq!(
+            std::fs::read_to_string(&path)?,
+            "tx,type,client,available_before,held_before,total_before,\
+             available_after,held_after,total_after,rejection\n\
+             1,deposit,1,0.0,0.0,0.0,1.0,0.0,1.0,\n\
+             2,deposit,2,0.0,0.0,0.0,2.0,0.0,2.0,\n\
+             3,deposit,1,1.0,0.0,1.0,3.0,0.0,3.0,\n\
+             4,withdrawal,1,3.0,0.0,3.0,1.5,0.0,1.5,\n\
+             5,withdrawal,2,,,,,,,\
+             Cannot process Withdrawal { amount: 3 }(5); \
+             Account 2: Not enough funds available: 3 > 2\n"
+        );
+        Ok(std::fs::remove_file(path)?)
+    }
+
+    #[test]
+    fn should_apply_txs_to_seeded_clients() -> anyhow::Result<()> {
+        let input = "type, client, tx, amount\n\
+                     withdrawal, 1, 1, 2.5\n\
+                     dispute, 1, 7\n\
+                     withdrawal, 2, 2, 1.0\n";
+        let mut engine = Engine::default();
+        engine.seed(Path::new("test_samples/seed.csv"))?;
+        engine.process_reader(input.as_bytes())?;
+        let client = engine.clients.get(&1).unwrap();
+        assert_eq!((client.available, client.held, client.total), (8.into(), 0.into(), 8.into()));
+        // locked accounts stay locked
+        let client = engine.clients.get(&2).unwrap();
+        assert_eq!((client.available, client.held, client.total), (3.into(), 1.into(), 4.into()));
+        assert!(client.locked);
+        assert_eq!(engine.first_seen, vec![1, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_apply_txs_again_with_checkpoint() -> anyhow::Result<()> {
+        let temp = |name: &str| {
+            std::env::temp_dir().join(format!("tx_fun_checkpoint_{}_{}", std::process::id(), name))
+        };
+        let (input, path) = (temp("input.csv"), temp("checkpoint.csv"));
+        std::fs::write(
+            &input,
+            "type,client,tx,amount\n\
+             deposit,1,1,5.0\n\
+             withdrawal,1,2,9.0\n\
+             deposit,1,3,1.0\n\
+             dispute,1,1,\n\
+             resolve,1,1,\n",
+        )?;
+        let run = || -> anyhow::Result<Engine> {
+            let mut engine = Engine::new(Config {
+                checkpoint: Some(path.clone()),
+                ..Default::default()
+            })?;
+            engine.process(vec![input.clone()])?;
+            Ok(engine)
+        };
+        let balances = |engine: &Engine| {
+            let c = &engine.clients[&1];
+            (c.available, c.held, c.total)
+        };
+        let result = (|| -> anyhow::Result<()> {
+            let engine = run()?;
+            assert_eq!(balances(&engine), (6.into(), 0.into(), 6.into()));
+            let checkpoint = std::fs::read_to_string(&path)?;
+            // failed withdrawal is not applied, so it is not recorded
+            assert_eq!(
+                checkpoint,
+                "row,type,client,tx,amount,available_delta,held_delta,total_delta,currency\n\
+                 1,deposit,1,1,5,,,,\n\
+                 3,deposit,1,3,1,,,,\n\
+                 4,dispute,1,1,,,,,\n\
+                 5,resolve,1,1,,,,,\n"
+            );
+
+            // rerun on the same input applies nothing again
+            let engine = run()?;
+            assert_eq!(balances(&engine), (6.into(), 0.into(), 6.into()));
+            assert_eq!((engine.stats.checkpointed, engine.stats.skipped), (5, 0));
+            assert_eq!(std::fs::read_to_string(&path)?, checkpoint);
+
+            // e.g. a crash after the dispute, the deposit is still disputed and resolved then
+            let lines: Vec<&str> = checkpoint.lines().take(4).collect();
+            std::fs::write(&path, lines.join("\n") + "\n")?;
+            let engine = run()?;
+            assert_eq!(balances(&engine), (6.into(), 0.into(), 6.into()));
+            assert_eq!((engine.stats.checkpointed, engine.stats.skipped), (4, 0));
+            assert_eq!(std::fs::read_to_string(&path)?, checkpoint);
+            Ok(())
+        })();
+        std::fs::remove_file(input)?;
+        std::fs::remove_file(path)?;
+        result
+    }
+
+    #[test]
+    fn should_reject_checkpoint_with_pending_disputes() {
+        let engine = Engine::new(Config {
+            checkpoint: Some("checkpoint.csv".into()),
+            pending_disputes: Some(1),
+            ..Default::default()
+        });
+        assert!(engine.is_err());
+    }
+
+    #[test]
+    fn should_output_clients_sorted_by_id_by_default() -> anyhow::Result<()> {
+        let output = || -> anyhow::Result<String> {
+            let mut engine = Engine::default();
+            engine.process_file("test_samples/nonexistent.csv".into())?;
+            let mut output = vec![];
+            engine.write_output(&mut output)?;
+            Ok(String::from_utf8(output)?)
+        };
+        let first = output()?;
+        assert_eq!(first, output()?);
+        assert_eq!(
+            first,
+            "client,available,held,total,locked\n\
+             1,0.49,0.0,0.49,false\n\
+             2,0.0,0.0,0.0,true\n\
+             3,1.14,3.14,4.28,false\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_sort_clients_with_tie_break_on_id() -> anyhow::Result<()> {
+        let mut engine = Engine::default();
+        engine.process_file("test_samples/sort.csv".into())?;
+        let ids = |key| -> Vec<u16> {
+            engine
+                .clients_snapshot_sorted(key)
+                .iter()
+                .map(|c| c.client_id)
+                .collect()
+        };
+        assert_eq!(ids(SortKey::Id), vec![1, 2, 3, 4]);
+        assert_eq!(ids(SortKey::Total), vec![3, 1, 4, 2]);
+        assert_eq!(ids(SortKey::Available), vec![1, 3, 4, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_write_cdc_events_versioned_by_tx_count() -> anyhow::Result<()> {
+        let mut engine = Engine::default();
+        engine.process_file("test_samples/nonexistent.csv".into())?;
+        let mut cdc = vec![];
+        engine.write_cdc(&mut cdc)?;
+        assert_eq!(
+            String::from_utf8(cdc)?,
+            "{\"client\":1,\"available\":0.49,\"held\":0.0,\"total\":0.49,\"locked\":false,\"version\":6}\n\
+             {\"client\":2,\"available\":0.0,\"held\":0.0,\"total\":0.0,\"locked\":true,\"version\":3}\n\
+             {\"client\":3,\"available\":1.14,\"held\":3.14,\"total\":4.28,\"locked\":false,\"version\":5}\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_track_total_held_over_time() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            holds_report: Some("holds.csv".into()),
+            holds_interval: NonZeroU64::new(2),
+            ..Default::default()
+        })?;
+        engine.process_file("test_samples/holds.csv".into())?;
+        let mut report = vec![];
+        engine.write_holds(&mut report)?;
+        assert_eq!(
+            String::from_utf8(report)?,
+            "row,total_held\n2,0.0\n4,3.0\n6,1.0\n"
+        );
+        Ok(())
     }
 
-    fn process_file(&mut self, input_file: PathBuf) -> anyhow::Result<()> {
-        let mut rdr = csv::ReaderBuilder::new()
-            .trim(csv::Trim::All)
-            .from_path(input_file)?;
+    #[test]
+    fn should_merge_accounts() -> anyhow::Result<()> {
+        let mut engine = Engine::default();
+        engine.process_file("test_samples/nonexistent.csv".into())?;
+        let merges = read_merges("test_samples/merges.csv".as_ref())?;
+        assert_eq!(merges, vec![(1, 3), (2, 4)]);
+        engine.merge_accounts(&merges)?;
+        assert_eq!(engine.clients.len(), 2);
+        let client = engine.clients.get(&3).unwrap();
+        assert_eq!(client.available, Decimal::from_f32(1.63).unwrap());
+        assert_eq!(client.held, Decimal::from_f32(3.14).unwrap());
+        assert_eq!(client.total, Decimal::from_f32(4.77).unwrap());
+        let client = engine.clients.get(&4).unwrap();
+        assert_eq!(client.client_id, 4);
+        assert_eq!(client.total, 0.into());
+        engine.ensure_balanced(0.into())?;
+        Ok(())
+    }
 
-        for result in rdr.deserialize() {
-            if let Err(_e) = self.process_row(result) {
-                // commenting out for better performance
-                // eprintln!("Error: {}", _e)
-            }
-        }
+    #[test]
+    fn should_take_processed_clients() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            policy: Policy {
+                allow_admin_freeze: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        })?;
+        engine.process(vec!["test_samples/freeze.csv".into()])?;
+        let clients = engine.into_clients();
+        assert_eq!(clients.len(), 1);
+        let client = &clients[&1];
+        assert_eq!(client.client_id, 1);
+        assert_eq!(client.available, 3.into());
+        assert_eq!(client.held, 0.into());
+        assert_eq!(client.total, 3.into());
+        assert!(!client.locked_or_frozen());
+        assert!(!client.frozen);
+        assert_eq!(client.version, 4);
         Ok(())
     }
 
-    fn process_row(&mut self, row: csv::Result<Tx>) -> anyhow::Result<()> {
-        let tx = row?;
-        tx.process(&mut self.clients)
-            .map_err(|e| anyhow!("Cannot process {:?}({}); {}", tx.tx_type, tx.tx_id, e))
+    #[test]
+    fn should_write_risk_after_chargeback() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            sort: SortKey::Id,
+            view: ViewOptions {
+                risk: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        })?;
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 5.0\n\
+                     deposit, 1, 2, 3.0\n\
+                     dispute, 1, 2,\n\
+                     chargeback, 1, 2,\n\
+                     deposit, 2, 3, 4.0\n\
+                     dispute, 2, 3,\n";
+        engine.process_reader(input.as_bytes())?;
+        let mut output = vec![];
+        engine.write_output(&mut output)?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            "client,available,held,total,locked,worst_case_total\n\
+             1,5.0,0.0,5.0,true,5.0\n\
+             2,0.0,4.0,4.0,false,0.0\n"
+        );
+        Ok(())
     }
 
-    fn output(&self) -> anyhow::Result<()> {
-        let mut wtr = csv::Writer::from_writer(std::io::stdout());
-        for c in self.clients.values() {
-            wtr.serialize(c)?;
+    #[test]
+    fn should_hide_empty_clients_unless_locked() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            sort: SortKey::Id,
+            hide_empty: true,
+            ..Default::default()
+        })?;
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 2.0\n\
+                     dispute, 1, 1,\n\
+                     chargeback, 1, 1,\n\
+                     deposit, 2, 2, 1.0\n\
+                     withdrawal, 2, 3, 1.0\n\
+                     deposit, 3, 4, 1.0\n";
+        engine.process_reader(input.as_bytes())?;
+        let mut output = vec![];
+        engine.write_output(&mut output)?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            "client,available,held,total,locked\n\
+             1,0.0,0.0,0.0,true\n\
+             3,1.0,0.0,1.0,false\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_write_raw_amounts() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            sort: SortKey::Id,
+            view: ViewOptions {
+                raw_amounts: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        })?;
+        engine.process_file("test_samples/example.csv".into())?;
+        let mut output = vec![];
+        engine.write_output(&mut output)?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            "# amounts are scaled by 10000\n\
+             client,available,held,total,locked\n\
+             1,15000,0,15000,false\n\
+             2,20000,0,20000,false\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_fail_run_on_total_above_ceiling() {
+        let mut engine = Engine::new(Config {
+            balance_floor: Some(1.into()),
+            balance_ceiling: Some(2.into()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            engine.run(vec!["test_samples/sort.csv".into()]).unwrap_err().to_string(),
+            "Clients with total out of bounds: 2 (3)"
+        );
+    }
+
+    #[test]
+    fn should_name_all_clients_out_of_bounds() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            balance_floor: Some(2.into()),
+            balance_ceiling: Some(2.into()),
+            ..Default::default()
+        })?;
+        engine.process_file("test_samples/sort.csv".into())?;
+        assert_eq!(
+            engine.ensure_within_bounds().unwrap_err().to_string(),
+            "Clients with total out of bounds: 2 (3), 3 (1)"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_sort_by_first_seen() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            sort: SortKey::FirstSeen,
+            ..Default::default()
+        })?;
+        engine.process_file("test_samples/sort.csv".into())?;
+        let mut output = vec![];
+        engine.write_output(&mut output)?;
+        let ids: Vec<&str> = std::str::from_utf8(&output)?
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap())
+            .collect();
+        assert_eq!(ids, ["4", "2", "3", "1"]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_write_deposits_report() -> anyhow::Result<()> {
+        let mut engine = Engine::default();
+        engine.process_file("test_samples/sort.csv".into())?;
+        let mut output = vec![];
+        engine.write_deposits(&mut output)?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            "client,tx,amount,disputed,state,dispute_count\n\
+             1,4,2.0,2.0,dispute,1\n\
+             2,2,3.0,0.0,ok,0\n\
+             3,3,1.0,0.0,ok,0\n\
+             4,1,2.0,0.0,ok,0\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_write_charged_back_deposits_in_final_state() -> anyhow::Result<()> {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 2.0\n\
+                     deposit, 1, 2, 1.0\n\
+                     dispute, 1, 1,\n\
+                     dispute, 1, 2,\n\
+                     chargeback, 1, 2,\n";
+        let mut engine = Engine::default();
+        engine.process_reader(input.as_bytes())?;
+        let mut output = vec![];
+        engine.write_deposits(&mut output)?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            "client,tx,amount,disputed,state,dispute_count\n\
+             1,1,2.0,2.0,dispute,1\n\
+             1,2,1.0,0.0,charged_back,1\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_write_disputes_report() -> anyhow::Result<()> {
+        let input = "type, client, tx, amount\n\
+                     deposit, 2, 1, 1.0\n\
+                     deposit, 2, 2, 1.0\n\
+                     deposit, 1, 3, 1.0\n\
+                     deposit, 2, 4, 1.0\n\
+                     dispute, 2, 4,\n\
+                     dispute, 2, 1,\n\
+                     dispute, 1, 3,\n\
+                     resolve, 1, 3,\n";
+        let mut engine = Engine::default();
+        engine.process_reader(input.as_bytes())?;
+        let mut output = vec![];
+        engine.write_disputes(&mut output)?;
+        assert_eq!(String::from_utf8(output)?, "client,tx\n2,1\n2,4\n");
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_report_charged_back_txs_as_disputed() -> anyhow::Result<()> {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 2.0\n\
+                     deposit, 1, 2, 1.0\n\
+                     dispute, 1, 2,\n\
+                     chargeback, 1, 2,\n\
+                     deposit, 2, 3, 5.0\n\
+                     withdrawal, 2, 4, 1.0\n\
+                     dispute, 2, 4,\n\
+                     chargeback, 2, 4,\n\
+                     deposit, 3, 5, 1.0\n\
+                     dispute, 3, 5,\n";
+        let mut engine = Engine::new(Config {
+            policy: Policy {
+                allow_withdrawal_disputes: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        })?;
+        engine.process_reader(input.as_bytes())?;
+        let mut output = vec![];
+        engine.write_disputes(&mut output)?;
+        assert_eq!(String::from_utf8(output)?, "client,tx\n3,5\n");
+        Ok(())
+    }
+
+    /// Deposit row as parsed from the input.
+    fn deposit_row(client_id: u16, tx_id: u32, amount: i64) -> anyhow::Result<Tx> {
+        Ok(Tx {
+            tx_type: TxType::Deposit {
+                amount: amount.into(),
+            },
+            client_id,
+            tx_id,
+            currency: None,
+        })
+    }
+
+    #[test]
+    fn should_roll_back_batch_leaving_client_inconsistent() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            batch_commit: NonZeroU64::new(2),
+            ..Default::default()
+        })?;
+        engine.handle_row(deposit_row(1, 1, 5))?;
+        engine.handle_row(deposit_row(1, 2, 1))?;
+        engine.handle_row(deposit_row(1, 3, 1))?;
+        // e.g. broken by a bug, no tx is allowed to do that
+        engine.clients.get_mut(&1).unwrap().available = (-7).into();
+        engine.handle_row(deposit_row(2, 4, 2))?;
+        engine.handle_row(deposit_row(1, 5, 3))?;
+        engine.commit_batch()?;
+        assert_eq!(engine.clients.len(), 1);
+        let client = &engine.clients[&1];
+        assert_eq!(client.available, 9.into());
+        assert_eq!(client.total, 9.into());
+        assert_eq!(engine.first_seen, [1]);
+        assert_eq!(engine.stats.rows, 5);
+        assert_eq!(engine.stats.skipped, 2);
+        assert_eq!(engine.stats.deposited, 9.into());
+        Ok(())
+    }
+
+    #[test]
+    fn should_roll_back_batch_of_checkpointed_and_skipped_rows() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            batch_commit: NonZeroU64::new(4),
+            ..Default::default()
+        })?;
+        engine.checkpointed_rows = 2;
+        engine.handle_row(deposit_row(1, 1, 5))?;
+        engine.handle_row(deposit_row(1, 2, 1))?;
+        engine.handle_row(deposit_row(1, 3, 1))?;
+        engine.clients.get_mut(&1).unwrap().available = (-7).into();
+        // duplicate tx id, skipped before the batch is rolled back
+        engine.handle_row(deposit_row(1, 3, 1))?;
+        assert!(engine.batch_start.is_none());
+        assert!(engine.clients.is_empty());
+        assert_eq!(engine.stats.rows, 4);
+        assert_eq!(engine.stats.checkpointed, 2);
+        assert_eq!(engine.stats.skipped, 2);
+        assert_eq!(engine.stats.deposited, 0.into());
+        Ok(())
+    }
+
+    #[test]
+    fn should_forget_rolled_back_batch() -> anyhow::Result<()> {
+        let temp = |name: &str| {
+            std::env::temp_dir().join(format!("tx_fun_rollback_{}_{}", std::process::id(), name))
+        };
+        let (input, checkpoint, holds) = (temp("input.csv"), temp("checkpoint"), temp("holds.csv"));
+        std::fs::write(&input, "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,1.0\n")?;
+        let mut engine = Engine::new(Config {
+            batch_commit: NonZeroU64::new(2),
+            checkpoint: Some(checkpoint.clone()),
+            holds_report: Some(holds.clone()),
+            ..Default::default()
+        })?;
+        let result = (|| -> anyhow::Result<()> {
+            engine.process(vec![input.clone()])?;
+            engine.handle_row(deposit_row(1, 3, 1))?;
+            engine.clients.get_mut(&1).unwrap().available = (-7).into();
+            engine.handle_row(deposit_row(2, 4, 2))?;
+            // rolled back deposit is not a duplicate, so it is applied again
+            engine.handle_row(deposit_row(2, 4, 2))?;
+            engine.commit_batch()?;
+            Ok(engine.checkpoint.as_mut().unwrap().flush()?)
+        })();
+        let written = std::fs::read_to_string(&checkpoint);
+        for path in [input, checkpoint, holds] {
+            std::fs::remove_file(path)?;
         }
+        result?;
+        assert_eq!(engine.clients[&2].total, 2.into());
+        assert_eq!(engine.stats.skipped, 2);
+        // rows of the rolled back batch are not recorded
+        let written = written?;
+        let rows: Vec<&str> = written.lines().skip(1).filter_map(|l| l.split(',').next()).collect();
+        assert_eq!(rows, ["1", "2", "5"]);
+        let rows: Vec<u64> = engine.holds.iter().map(|(row, _)| *row).collect();
+        assert_eq!(rows, [1, 2, 5]);
+        Ok(())
+    }
 
-        Ok(wtr.flush()?)
+    #[test]
+    fn should_reject_batch_commit_with_audit() {
+        let engine = Engine::new(Config {
+            batch_commit: NonZeroU64::new(2),
+            audit: Some("audit.csv".into()),
+            ..Default::default()
+        });
+        assert!(engine.is_err());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::tx::TxType;
-    use rust_decimal::{Decimal, prelude::FromPrimitive};
-    use rand::{thread_rng, Rng};
-    use serde::Serialize;
+    #[test]
+    fn should_write_output_to_file() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("tx_fun_output_{}.csv", std::process::id()));
+        let mut engine = Engine::new(Config {
+            sort: SortKey::Id,
+            output: Some(path.clone()),
+            ..Default::default()
+        })?;
+        engine.run(vec!["test_samples/example.csv".into()])?;
+        let output = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,1.5,0.0,1.5,false\n2,2.0,0.0,2.0,false\n"
+        );
+        Ok(())
+    }
 
-    fn random() -> Decimal {
-        let r: Decimal = thread_rng().gen_range(1..1_000_000_000).into();
-        r / Decimal::from(10_000)
+    #[test]
+    fn should_fail_on_output_which_cannot_be_created() {
+        let mut engine = Engine::new(Config {
+            output: Some("test_samples/nonexistent/output.csv".into()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            engine.run(vec!["test_samples/example.csv".into()]).unwrap_err().to_string(),
+            "Cannot create output test_samples/nonexistent/output.csv"
+        );
     }
 
-    fn assert_example_result(engine: &mut Engine) {
-        let client = engine.clients.get(&1).unwrap();
-        assert_eq!(client.available, Decimal::from_f32(1.5).unwrap());
-        assert_eq!(client.held, 0.into());
-        assert_eq!(client.total, Decimal::from_f32(1.5).unwrap());
-        let client = engine.clients.get(&2).unwrap();
-        assert_eq!(client.available, 2.into());
-        assert_eq!(client.held, 0.into());
-        assert_eq!(client.total, 2.into());
+    #[test]
+    fn should_process_input_without_file() -> anyhow::Result<()> {
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 1.5\nwrong\n";
+        let mut engine = Engine::new(Config {
+            checksum: Some("0".repeat(64)),
+            ..Default::default()
+        })?;
+        let e = engine.process_input("stdin", input.as_bytes()).unwrap_err();
+        assert!(e.to_string().starts_with("Checksum mismatch for stdin"));
+        assert_eq!(engine.clients[&1].available, Decimal::from_f32(1.5).unwrap());
+        assert_eq!(engine.stats.skipped, 1);
+        Ok(())
     }
 
     #[test]
-    fn should_handle_example() -> anyhow::Result<()> {
+    fn should_process_files_in_order() -> anyhow::Result<()> {
         let mut engine = Engine::default();
-        engine.process_file("test_samples/example.csv".into())?;
-        assert_example_result(&mut engine);
+        engine.process_files(vec![
+            "test_samples/example.csv".into(),
+            "test_samples/continued.csv".into(),
+        ])?;
+        assert_eq!(engine.clients.len(), 3);
+        let client = &engine.clients[&1];
+        assert_eq!(client.available, Decimal::from_f32(0.5).unwrap());
+        assert_eq!(client.held, 2.into());
+        assert_eq!(client.total, Decimal::from_f32(2.5).unwrap());
         Ok(())
     }
 
     #[test]
-    fn should_handle_spaceless_format() -> anyhow::Result<()> {
+    fn should_abort_or_skip_on_missing_file() -> anyhow::Result<()> {
+        let files: Vec<PathBuf> = vec![
+            "test_samples/example.csv".into(),
+            "test_samples/missing.csv".into(),
+            "test_samples/continued.csv".into(),
+        ];
         let mut engine = Engine::default();
-        engine.process_file("test_samples/spaceless.csv".into())?;
+        assert!(io_error_kind(&engine.process_files(files.clone()).unwrap_err()).is_some());
+        assert_eq!(engine.clients.len(), 2);
+
+        let mut engine = Engine::new(Config {
+            skip_bad_inputs: true,
+            ..Default::default()
+        })?;
+        engine.process_files(files)?;
+        assert_eq!(engine.clients.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn should_reset_state_but_not_config() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            sort: SortKey::Total,
+            ..Default::default()
+        })?;
+        engine.process_file("test_samples/example.csv".into())?;
+        engine.reset();
+        assert!(engine.clients.is_empty());
+        assert_eq!(engine.stats.rows, 0);
+        engine.process_file("test_samples/example.csv".into())?;
         assert_example_result(&mut engine);
+        assert_eq!(engine.config.sort, SortKey::Total);
         Ok(())
     }
 
     #[test]
-    fn should_handle_spacefull_format() -> anyhow::Result<()> {
+    fn should_seed_again_after_reset() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            seed: Some("test_samples/seed.csv".into()),
+            ..Default::default()
+        })?;
+        engine.process(vec!["test_samples/example.csv".into()])?;
+        // seeded once, even if processed again, the txs are skipped as duplicates
+        engine.process(vec!["test_samples/example.csv".into()])?;
+        assert_eq!(engine.clients.get(&1).unwrap().total, 12.into());
+        engine.reset();
+        engine.process(vec!["test_samples/example.csv".into()])?;
+        assert_eq!(engine.clients.get(&1).unwrap().total, 12.into());
+        assert!(engine.clients.get(&2).unwrap().locked);
+        Ok(())
+    }
+
+    #[test]
+    fn should_write_json_array() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            sort: SortKey::Id,
+            output_format: OutputFormat::Json,
+            ..Default::default()
+        })?;
+        engine.process_file("test_samples/example.csv".into())?;
+        let mut output = vec![];
+        engine.write_output(&mut output)?;
+        let json: serde_json::Value = serde_json::from_slice(&output)?;
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"client": 1, "available": 1.5, "held": 0.0, "total": 1.5, "locked": false},
+                {"client": 2, "available": 2.0, "held": 0.0, "total": 2.0, "locked": false},
+            ])
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "proto")]
+    #[test]
+    fn should_write_proto_messages() -> anyhow::Result<()> {
+        use crate::proto::ClientBalance;
+        use prost::Message;
+
+        let mut engine = Engine::new(Config {
+            sort: SortKey::Id,
+            output_format: OutputFormat::Proto,
+            ..Default::default()
+        })?;
+        engine.process_file("test_samples/example.csv".into())?;
+        let mut output = vec![];
+        engine.write_output(&mut output)?;
+        let mut buf = output.as_slice();
+        let first = ClientBalance::decode_length_delimited(&mut buf)?;
+        let second = ClientBalance::decode_length_delimited(&mut buf)?;
+        assert!(buf.is_empty());
+        assert_eq!((first.client, first.total), (1, 15000));
+        assert_eq!((second.client, second.total), (2, 20000));
+        Ok(())
+    }
+
+    #[test]
+    fn should_count_rows_by_type() -> anyhow::Result<()> {
         let mut engine = Engine::default();
-        engine.process_file("test_samples/spacefull.csv".into())?;
-        assert_example_result(&mut engine);
+        engine.process_file("test_samples/wrong.csv".into())?;
+        assert_eq!(
+            engine.stats_line(),
+            "rows 5, deposit 2, withdrawal 1, dispute 0, resolve 0, chargeback 0, skipped 3, locked 0"
+        );
         Ok(())
     }
 
     #[test]
-    fn should_skip_wrong_lines_in_csv_but_process_rest() -> anyhow::Result<()> {
+    fn should_summarize_run_as_json() -> anyhow::Result<()> {
         let mut engine = Engine::default();
         engine.process_file("test_samples/wrong.csv".into())?;
-        let client = engine.clients.get(&1).unwrap();
-        assert_eq!(client.available, 1.into());
-        assert_eq!(client.held, 0.into());
-        assert_eq!(client.total, 1.into());
-        let client = engine.clients.get(&2).unwrap();
-        assert_eq!(client.available, 2.into());
-        assert_eq!(client.held, 0.into());
-        assert_eq!(client.total, 2.into());
+        assert_eq!(
+            serde_json::to_value(engine.summary())?,
+            serde_json::json!({
+                "rows": 5,
+                "errors": 3,
+                "checkpointed": 0,
+                "clients": 2,
+                "locked": 0,
+                "deposited": 3.0,
+                "withdrawn": 0.0,
+                "chargedback": 0.0,
+                "held": 0.0,
+            })
+        );
         Ok(())
     }
 
     #[test]
-    fn should_skip_nonexistent_accounts() -> anyhow::Result<()> {
+    fn should_skip_duplicate_tx_ids() -> anyhow::Result<()> {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 1.0\n\
+                     deposit, 1, 1, 5.0\n\
+                     deposit, 2, 2, 3.0\n\
+                     withdrawal, 1, 2, 0.5\n\
+                     dispute, 1, 1,\n";
         let mut engine = Engine::default();
+        engine.process_reader(input.as_bytes())?;
+        let client = &engine.clients[&1];
+        assert_eq!(client.available, 0.into());
+        assert_eq!(client.held, 1.into());
+        assert_eq!(client.total, 1.into());
+        assert_eq!(engine.stats.skipped, 2);
+        assert_eq!(
+            engine
+                .process_row(Ok::<_, anyhow::Error>(Tx {
+                    tx_type: TxType::Deposit { amount: 1.into() },
+                    client_id: 1,
+                    tx_id: 1,
+                    currency: None,
+                }))
+                .unwrap_err()
+                .to_string(),
+            "Duplicate transaction 1"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_write_json_map_with_sorted_keys() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            json_map: true,
+            ..Default::default()
+        })?;
+        engine.process_file("test_samples/sort.csv".into())?;
+        let mut output = vec![];
+        engine.write_output(&mut output)?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            "{\"1\":{\"available\":0.0,\"held\":2.0,\"total\":2.0,\"locked\":false},\
+             \"2\":{\"available\":3.0,\"held\":0.0,\"total\":3.0,\"locked\":false},\
+             \"3\":{\"available\":1.0,\"held\":0.0,\"total\":1.0,\"locked\":false},\
+             \"4\":{\"available\":2.0,\"held\":0.0,\"total\":2.0,\"locked\":false}}\n"
+        );
+        Ok(())
+    }
+
+    struct BrokenPipe;
+
+    impl Write for BrokenPipe {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[test]
+    fn should_end_ledger_with_final_balances() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            ledger: Some("ledger.csv".into()),
+            ..Default::default()
+        })?;
         engine.process_file("test_samples/nonexistent.csv".into())?;
-        let client = engine.clients.get(&1).unwrap();
-        assert_eq!(client.available, Decimal::from_f32(0.49).unwrap());
-        assert_eq!(client.held, 0.into());
-        assert_eq!(client.total, Decimal::from_f32(0.49).unwrap());
-        let client = engine.clients.get(&2).unwrap();
-        assert_eq!(client.available, Decimal::from_f32(0.).unwrap());
-        assert_eq!(client.held, Decimal::from_f32(0.).unwrap());
-        assert_eq!(client.total, Decimal::from_f32(0.).unwrap());
-        let client = engine.clients.get(&3).unwrap();
-        assert_eq!(client.available, Decimal::from_f32(1.14).unwrap());
-        assert_eq!(client.held, Decimal::from_f32(3.14).unwrap());
-        assert_eq!(client.total, Decimal::from_f32(4.28).unwrap());
+        assert_eq!(
+            engine.ledger[0],
+            LedgerRow {
+                client: 1,
+                tx: 1,
+                operation: "deposit",
+                delta: Decimal::from_f32(1.1).unwrap(),
+                available: Decimal::from_f32(1.1).unwrap(),
+                held: 0.into(),
+                total: Decimal::from_f32(1.1).unwrap(),
+            }
+        );
+        for c in engine.clients.values() {
+            let last = engine.ledger.iter().rfind(|r| r.client == c.client_id).unwrap();
+            assert_eq!((last.available, last.held, last.total), (c.available, c.held, c.total));
+        }
+        let mut ledger = vec![];
+        engine.write_ledger(&mut ledger)?;
+        assert!(String::from_utf8(ledger)?.starts_with(
+            "client,tx,operation,delta,available,held,total\n1,1,deposit,1.1,1.1,0.0,1.1\n"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn should_process_by_workers_as_serially() -> anyhow::Result<()> {
+        let balances = |engine: &Engine| -> Vec<(u16, Decimal, Decimal, Decimal, bool)> {
+            let clients = engine.clients_snapshot_sorted(SortKey::Id);
+            clients.iter().map(|c| (c.client_id, c.available, c.held, c.total, c.locked)).collect()
+        };
+        for files in [
+            vec!["test_samples/nonexistent.csv".into()],
+            vec!["test_samples/example.csv".into(), "test_samples/continued.csv".into()],
+        ] {
+            let mut serial = Engine::default();
+            serial.process(files.clone())?;
+            let mut sharded = Engine::new(Config {
+                workers: 3,
+                ..Default::default()
+            })?;
+            sharded.process(files)?;
+            assert_eq!(balances(&sharded), balances(&serial));
+            assert_eq!(sharded.first_seen, serial.first_seen);
+            assert_eq!(sharded.stats.rows, serial.stats.rows);
+            assert_eq!(sharded.stats.skipped, serial.stats.skipped);
+            assert_eq!(sharded.stats.held, serial.stats.held);
+            assert_eq!(sharded.stats.net(), serial.stats.net());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_options_ignored_by_workers() {
+        let engine = Engine::new(Config {
+            workers: 2,
+            strict: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            engine.err().map(|e| e.to_string()).as_deref(),
+            Some("Workers cannot be combined with strict")
+        );
+        let engine = Engine::new(Config {
+            workers: 1,
+            audit: Some("audit.csv".into()),
+            ..Default::default()
+        });
+        assert!(engine.is_ok());
+    }
+
+    #[test]
+    fn should_not_merge_client_of_more_shards() -> anyhow::Result<()> {
+        let mut engine = Engine::default();
+        let shards = (0..2)
+            .map(|_| Shard {
+                clients: HashMap::from([(1, Client::create(1))]),
+                ..Default::default()
+            })
+            .collect();
+        assert_eq!(
+            engine.merge_shards(shards).unwrap_err().to_string(),
+            "Client 1 processed by more than one worker"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_retry_dispute_arriving_before_deposit() -> anyhow::Result<()> {
+        let input = "type,client,tx,amount\n\
+            dispute,1,1,\ndeposit,1,1,2.0\n\
+            dispute,1,3,\nresolve,1,3,\ndeposit,1,3,1.0\nchargeback,1,4,\n";
+        let mut engine = Engine::new(Config {
+            pending_disputes: Some(2),
+            ..Default::default()
+        })?;
+        engine.process_input("stdin", input.as_bytes())?;
+        assert_eq!(engine.clients[&1].held, 2.into());
+        assert_eq!(engine.clients[&1].available, 1.into());
+        assert_eq!(engine.clients[&1].disputed_tx_ids(), [1]);
+        assert_eq!(engine.pending.len(), 1);
+        assert_eq!(engine.stats.skipped, 0);
+        engine.skip_pending()?;
+        assert_eq!(engine.stats.skipped, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn should_skip_dispute_when_pending_queue_is_full() -> anyhow::Result<()> {
+        let input = "type,client,tx,amount\n\
+            dispute,1,1,\ndispute,1,2,\ndeposit,1,1,2.0\ndeposit,1,2,1.0\n";
+        let mut engine = Engine::new(Config {
+            pending_disputes: Some(1),
+            ..Default::default()
+        })?;
+        engine.process_input("stdin", input.as_bytes())?;
+        assert_eq!(engine.clients[&1].disputed_tx_ids(), [1]);
+        assert_eq!(engine.stats.skipped, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn should_abort_on_inconsistent_funds_when_strict() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            strict: true,
+            ..Default::default()
+        })?;
+        let deposit = |tx_id| Tx {
+            tx_type: TxType::Deposit { amount: 1.into() },
+            client_id: 1,
+            tx_id,
+            currency: None,
+        };
+        engine.handle_row(Ok::<_, anyhow::Error>(deposit(1)))?;
+        // off-by-one which a buggy tx might introduce
+        engine.clients.get_mut(&1).unwrap().total += Decimal::from(1);
+        let e = engine.handle_row(Ok::<_, anyhow::Error>(deposit(2))).unwrap_err();
+        assert_eq!(
+            format!("{:#}", e),
+            "Invariant violated: Account 1: Inconsistent funds: available 2 + held 0 != total 3"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_process_txs_built_in_memory() -> anyhow::Result<()> {
+        let tx = |tx_type, client_id, tx_id| Tx {
+            tx_type,
+            client_id,
+            tx_id,
+            currency: None,
+        };
+        let txs = vec![
+            tx(TxType::Deposit { amount: 1.into() }, 1, 1),
+            tx(TxType::Deposit { amount: 2.into() }, 2, 2),
+            tx(TxType::Deposit { amount: 2.into() }, 1, 3),
+            tx(TxType::Withdrawal { amount: Decimal::new(15, 1) }, 1, 4),
+            tx(TxType::Withdrawal { amount: 3.into() }, 2, 5),
+        ];
+        let mut engine = Engine::default();
+        engine.process_txs(txs)?;
+        assert_example_result(&mut engine);
+        assert_eq!((engine.stats.rows, engine.stats.skipped), (5, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn should_apply_txs_one_by_one() -> anyhow::Result<()> {
+        let mut engine = Engine::default();
+        let tx = |tx_type, tx_id| Tx {
+            tx_type,
+            client_id: 4,
+            tx_id,
+            currency: None,
+        };
+        let snapshot = engine.apply(tx(TxType::Deposit { amount: 3.into() }, 1))?;
+        assert_eq!(
+            snapshot,
+            ClientSnapshot {
+                client_id: 4,
+                available: 3.into(),
+                held: 0.into(),
+                total: 3.into(),
+                locked: false,
+            }
+        );
+        let snapshot = engine.apply(tx(TxType::Dispute { amount: None }, 1))?;
+        assert_eq!((snapshot.available, snapshot.held), (0.into(), 3.into()));
+        assert!(engine.apply(tx(TxType::Chargeback, 1))?.locked);
+        assert_eq!(
+            engine.apply(tx(TxType::Deposit { amount: 1.into() }, 2)).unwrap_err().to_string(),
+            "Cannot process Deposit { amount: 1 }(2); Account 4 is locked"
+        );
+        assert_eq!(engine.stats.rows, 4);
+        assert_eq!(engine.stats.skipped, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn should_evict_least_recently_touched_client() -> anyhow::Result<()> {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,1.0\ndeposit,2,2,2.0\ndeposit,1,3,1.0\n\
+            deposit,3,4,3.0\ndispute,2,2,\ndeposit,4,5,4.0\n";
+        let mut engine = Engine::default();
+        let mut output = vec![];
+        engine.process_reader_with_limit(input.as_bytes(), 2, &mut output)?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            "client,available,held,total,locked\n\
+             2,2.0,0.0,2.0,false\n\
+             1,2.0,0.0,2.0,false\n\
+             3,3.0,0.0,3.0,false\n\
+             4,4.0,0.0,4.0,false\n"
+        );
+        // dispute of the evicted client's deposit
+        assert_eq!(engine.stats.skipped, 1);
+        Ok(())
+    }
+
+    /// Remembers what was written at every flush.
+    #[derive(Default)]
+    struct FlushRecorder {
+        written: Vec<u8>,
+        flushed: Vec<String>,
+    }
+
+    impl Write for FlushRecorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushed.push(String::from_utf8_lossy(&self.written).into());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_flush_output_every_n_clients() -> anyhow::Result<()> {
+        let mut engine = Engine::new(Config {
+            sort: SortKey::Id,
+            flush_every: Some(2),
+            ..Default::default()
+        })?;
+        engine.process_file("test_samples/sort.csv".into())?;
+        let mut recorder = FlushRecorder::default();
+        engine.write_output(&mut recorder)?;
+        let header = "client,available,held,total,locked\n";
+        let first_two = "1,0.0,2.0,2.0,false\n2,3.0,0.0,3.0,false\n";
+        let rest = "3,1.0,0.0,1.0,false\n4,2.0,0.0,2.0,false\n";
+        assert_eq!(recorder.flushed[0], format!("{}{}", header, first_two));
+        assert_eq!(
+            recorder.flushed.last().unwrap(),
+            &format!("{}{}{}", header, first_two, rest)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_recognize_broken_pipe_on_output() -> anyhow::Result<()> {
+        let mut engine = Engine::default();
+        engine.process_file("test_samples/example.csv".into())?;
+        let e = engine.write_output(BrokenPipe).unwrap_err();
+        assert!(is_broken_pipe(&e));
+        assert!(!is_broken_pipe(&anyhow!("Other error")));
         Ok(())
     }
 
@@ -133,64 +3230,76 @@ mod tests {
     fn performance_test() -> anyhow::Result<()> {
         let mut engine = Engine::default();
         let mut rng = thread_rng();
-
-        for _ in 0..10_000_000 {
+        engine.process_txs((0..10_000_000).map(|_| {
             let tx_type = match rng.gen_range(0..5) {
                 0 => TxType::Deposit { amount: random() },
                 1 => TxType::Withdrawal { amount: random() },
-                2 => TxType::Dispute,
+                2 => TxType::Dispute { amount: None },
                 3 => TxType::Resolve,
                 4 => TxType::Chargeback,
                 _ => unreachable!(),
             };
+            Tx {
+                tx_type,
+                client_id: rng.gen_range(1..10_000),
+                tx_id: rng.gen_range(1..100_000),
+                currency: None,
+            }
+        }))
+    }
 
-            let client_id = rng.gen_range(1..10_000);
-            let tx_id = rng.gen_range(1..100_000);
-            let tx = Tx {
-                tx_type: tx_type.clone(),
-                client_id,
-                tx_id,
-            };
-
-            if let Err(_e) = engine.process_row(csv::Result::Ok(tx)) {
-                // eprintln!("Error: {}", _e)
+    fn write_random_csv(path: &Path) -> anyhow::Result<()> {
+        let mut wtr = std::io::BufWriter::new(File::create(path)?);
+        let mut rng = thread_rng();
+        writeln!(wtr, "type, client, tx, amount")?;
+        for tx in 0..1_000_000 {
+            let client = rng.gen_range(1..1_000);
+            match rng.gen_range(0..5) {
+                0 => writeln!(wtr, "deposit, {}, {}, {:0.4}", client, tx, random())?,
+                1 => writeln!(wtr, "withdrawal, {}, {}, {:0.4}", client, tx, random())?,
+                2 => writeln!(wtr, "dispute, {}, {},", client, rng.gen_range(0..tx + 1))?,
+                3 => writeln!(wtr, "resolve, {}, {},", client, rng.gen_range(0..tx + 1))?,
+                _ => writeln!(wtr, "chargeback, {}, {},", client, rng.gen_range(0..tx + 1))?,
             }
         }
-        Ok(())
+        Ok(wtr.flush()?)
     }
 
     #[test]
     #[ignore]
-    fn generate_test_file() -> anyhow::Result<()> {
-        #[derive(Clone, Debug, Serialize)]
-        pub(crate) struct Tx {
-            r#type: String,
-            client: u16,
-            tx: u32,
-            amount: String,
+    fn fast_parse_performance_test() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join("tx_fun_fast_parse.csv");
+        write_random_csv(&path)?;
+
+        for engine in [Engine::default(), fast_parse_engine()].iter_mut() {
+            let start = std::time::Instant::now();
+            engine.process_file(path.clone())?;
+            println!("fast_parse={}: {:?}", engine.config.fast_parse, start.elapsed());
         }
+        Ok(std::fs::remove_file(path)?)
+    }
 
-        let mut wtr = csv::Writer::from_path("tst.csv")?;
-        let mut rng = thread_rng();
-        for _ in 0..1_000_000 {
-            let (tx_type, amount) = match rng.gen_range(0..5) {
-                0 => ("deposit", format!("{:0.4}", random())),
-                1 => ("withdrawal", format!("{:0.4}", random())),
-                2 => ("dispute", "".into()),
-                3 => ("resolve", "".into()),
-                4 => ("chargeback", "".into()),
-                _ => unreachable!(),
-            };
-            let client = rng.gen_range(1..1_000);
-            let tx: u32 = rng.gen_range(1..10_000);
+    #[test]
+    #[ignore]
+    fn workers_performance_test() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join("tx_fun_workers.csv");
+        write_random_csv(&path)?;
 
-            wtr.serialize(Tx {
-                r#type: tx_type.to_string(),
-                client,
-                tx,
-                amount,
-            })?
+        for workers in [1, 2, 4, 8] {
+            let mut engine = Engine::new(Config {
+                workers,
+                ..Default::default()
+            })?;
+            let start = std::time::Instant::now();
+            engine.process_file(path.clone())?;
+            println!("workers={}: {:?}", workers, start.elapsed());
         }
-        Ok(wtr.flush()?)
+        Ok(std::fs::remove_file(path)?)
+    }
+
+    #[test]
+    #[ignore]
+    fn generate_test_file() -> anyhow::Result<()> {
+        Generator::default().generate(File::create("tst.csv")?)
     }
 }
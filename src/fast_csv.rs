@@ -0,0 +1,163 @@
+use anyhow::{anyhow, bail};
+use csv_core::ReadRecordResult;
+use std::io::BufRead;
+use std::str::FromStr;
+
+use crate::amount;
+use crate::input::BOM;
+use crate::tx::{self, Tx, TxType};
+
+/// CSV reader deserializing txs by hand with `csv_core`, skipping serde.
+/// All fields are trimmed, as with `csv::Trim::All`, and the BOM is skipped, like `csv` does.
+pub(crate) struct FastCsvReader<R> {
+    input: R,
+    rdr: csv_core::Reader,
+    record: Vec<u8>,
+    ends: Vec<usize>,
+    // number of fields in the header and indices of known columns
+    width: usize,
+    tx_type: Option<usize>,
+    client: Option<usize>,
+    tx: Option<usize>,
+    amount: Option<usize>,
+    currency: Option<usize>,
+    deltas: [Option<usize>; 3],
+    allow_fractions: bool,
+}
+
+impl<R: BufRead> FastCsvReader<R> {
+    pub(crate) fn new(mut input: R, delimiter: u8, allow_fractions: bool) -> anyhow::Result<Self> {
+        if input.fill_buf()?.starts_with(BOM) {
+            input.consume(BOM.len());
+        }
+        let mut reader = FastCsvReader {
+            input,
+            rdr: csv_core::ReaderBuilder::new().delimiter(delimiter).build(),
+            record: vec![0; 1024],
+            ends: vec![0; 8],
+            width: 0,
+            tx_type: None,
+            client: None,
+            tx: None,
+            amount: None,
+            currency: None,
+            deltas: [None; 3],
+            allow_fractions,
+        };
+        if let Some(width) = reader.read_record()? {
+            reader.width = width;
+            for i in 0..width {
+                match reader.field(i) {
+                    b"type" => reader.tx_type = Some(i),
+                    b"client" => reader.client = Some(i),
+                    b"tx" => reader.tx = Some(i),
+                    b"amount" => reader.amount = Some(i),
+                    b"currency" => reader.currency = Some(i),
+                    b"available_delta" => reader.deltas[0] = Some(i),
+                    b"held_delta" => reader.deltas[1] = Some(i),
+                    b"total_delta" => reader.deltas[2] = Some(i),
+                    _ => {}
+                }
+            }
+            let header: Vec<String> = (0..width)
+                .map(|i| String::from_utf8_lossy(reader.field(i)).into_owned())
+                .collect();
+            tx::ensure_columns(header.iter().map(String::as_str))?;
+        }
+        Ok(reader)
+    }
+
+    /// Reads next record into the buffer, returns number of its fields or `None` at the end.
+    fn read_record(&mut self) -> anyhow::Result<Option<usize>> {
+        let (mut outlen, mut endlen) = (0, 0);
+        loop {
+            let input = self.input.fill_buf()?;
+            let (result, nin, nout, nend) = self.rdr.read_record(
+                input,
+                &mut self.record[outlen..],
+                &mut self.ends[endlen..],
+            );
+            self.input.consume(nin);
+            outlen += nout;
+            endlen += nend;
+            match result {
+                ReadRecordResult::InputEmpty => {}
+                ReadRecordResult::OutputFull => self.record.resize(self.record.len() * 2, 0),
+                ReadRecordResult::OutputEndsFull => self.ends.resize(self.ends.len() * 2, 0),
+                ReadRecordResult::Record => return Ok(Some(endlen)),
+                ReadRecordResult::End => return Ok(None),
+            }
+        }
+    }
+
+    fn field(&self, i: usize) -> &[u8] {
+        let start = if i == 0 { 0 } else { self.ends[i - 1] };
+        self.record[start..self.ends[i]].trim_ascii()
+    }
+
+    fn column<T: FromStr>(&self, column: Option<usize>, name: &str) -> anyhow::Result<T> {
+        let field = self.field(column.ok_or_else(|| anyhow!("Missing column {}", name))?);
+        std::str::from_utf8(field)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| anyhow!("Invalid {} {}", name, String::from_utf8_lossy(field)))
+    }
+
+    fn parse(&self, width: usize) -> anyhow::Result<Tx> {
+        if width > self.width {
+            bail!("Found record with {} fields, but the header has {}", width, self.width)
+        }
+        // trailing fields might be left out, like `csv` does for flexible records
+        let present = |column: Option<usize>| column.filter(|i| *i < width);
+        let value = |column| present(column).map(|i| self.field(i)).filter(|f| !f.is_empty());
+        let tx_id = self.column(present(self.tx), "tx")?;
+        let parse = |column, tx_type, name| -> anyhow::Result<_> {
+            let field = tx::required(value(column), tx_type, tx_id, name)?;
+            amount::parse(std::str::from_utf8(field)?, self.allow_fractions)
+        };
+        let amount = |tx_type| parse(self.amount, tx_type, "amount");
+        let tx_type = match present(self.tx_type).map(|i| self.field(i)) {
+            Some(b"deposit") => TxType::Deposit { amount: amount("deposit")? },
+            Some(b"withdrawal") => TxType::Withdrawal { amount: amount("withdrawal")? },
+            Some(b"dispute") => TxType::Dispute {
+                amount: match value(self.amount) {
+                    Some(_) => Some(amount("dispute")?),
+                    None => None,
+                },
+            },
+            Some(b"resolve") => TxType::Resolve,
+            Some(b"chargeback") => TxType::Chargeback,
+            Some(b"freeze") => TxType::Freeze,
+            Some(b"unfreeze") => TxType::Unfreeze,
+            Some(b"delta") => TxType::Delta {
+                available_delta: parse(self.deltas[0], "delta", "available_delta")?,
+                held_delta: parse(self.deltas[1], "delta", "held_delta")?,
+                total_delta: parse(self.deltas[2], "delta", "total_delta")?,
+            },
+            Some(b"adjustment") => TxType::Adjustment { amount: amount("adjustment")? },
+            Some(other) => bail!("Unknown tx type {}", String::from_utf8_lossy(other)),
+            None => bail!("Missing column type"),
+        };
+        Ok(Tx {
+            tx_type,
+            client_id: self.column(present(self.client), "client")?,
+            tx_id,
+            currency: match value(self.currency) {
+                Some(_) => Some(self.column(self.currency, "currency")?),
+                None => None,
+            },
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for FastCsvReader<R> {
+    type Item = anyhow::Result<Tx>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_record() {
+            Ok(Some(width)) => Some(self.parse(width)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use anyhow::{anyhow, ensure};
+use rand::distributions::WeightedIndex;
+use rand::prelude::*;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::io::Write;
+use std::str::FromStr;
+
+/// Relative frequencies of deposits, withdrawals, disputes, resolves and chargebacks.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Weights([u32; 5]);
+
+impl Default for Weights {
+    fn default() -> Self {
+        Weights([1; 5])
+    }
+}
+
+impl FromStr for Weights {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let weights = s
+            .split(',')
+            .map(|w| w.trim().parse())
+            .collect::<Result<Vec<u32>, _>>()?;
+        ensure!(
+            weights.len() == 5,
+            "Expected 5 weights (deposit,withdrawal,dispute,resolve,chargeback), got {}",
+            s
+        );
+        ensure!(weights.iter().any(|w| *w > 0), "At least one weight must be positive");
+        Ok(Weights([weights[0], weights[1], weights[2], weights[3], weights[4]]))
+    }
+}
+
+/// Generator of random txs files for benchmarks.
+#[derive(Debug)]
+pub(crate) struct Generator {
+    pub(crate) rows: usize,
+    pub(crate) clients: u16,
+    pub(crate) weights: Weights,
+    /// Disputes, resolves and chargebacks reference deposits generated before,
+    /// otherwise their tx ids are random.
+    pub(crate) valid_disputes: bool,
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Generator {
+            rows: 1_000_000,
+            clients: 1_000,
+            weights: Default::default(),
+            valid_disputes: false,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Row {
+    r#type: &'static str,
+    client: u16,
+    tx: u32,
+    amount: String,
+}
+
+impl Generator {
+    /// Fails before writing anything if there are no clients, too many rows or no weights.
+    pub(crate) fn generate<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        ensure!(self.clients > 0, "Clients must be positive");
+        ensure!(self.weights.0.iter().any(|w| *w > 0), "At least one weight must be positive");
+        let rows = u32::try_from(self.rows)
+            .map_err(|_| anyhow!("Too many rows {}, at most {}", self.rows, u32::MAX))?;
+        let types = ["deposit", "withdrawal", "dispute", "resolve", "chargeback"];
+        let dist = WeightedIndex::new(self.weights.0)?;
+        let mut wtr = csv::Writer::from_writer(writer);
+        let mut rng = thread_rng();
+        // (client, tx) of the generated deposits
+        let mut deposits: Vec<(u16, u32)> = vec![];
+        for tx in 1..=rows {
+            let r#type = types[dist.sample(&mut rng)];
+            let client = rng.gen_range(1..=self.clients);
+            let row = match r#type {
+                "deposit" | "withdrawal" => {
+                    if r#type == "deposit" {
+                        deposits.push((client, tx));
+                    }
+                    Row {
+                        r#type,
+                        client,
+                        tx,
+                        amount: format!("{:0.4}", random_amount(&mut rng)),
+                    }
+                }
+                _ => {
+                    let (client, tx) = match deposits.choose(&mut rng) {
+                        Some(deposit) if self.valid_disputes => *deposit,
+                        _ => (client, rng.gen_range(1..=rows)),
+                    };
+                    Row {
+                        r#type,
+                        client,
+                        tx,
+                        amount: "".into(),
+                    }
+                }
+            };
+            wtr.serialize(row)?;
+        }
+        Ok(wtr.flush()?)
+    }
+}
+
+fn random_amount<R: Rng>(rng: &mut R) -> Decimal {
+    let r: Decimal = rng.gen_range(1..1_000_000_000).into();
+    r / Decimal::from(10_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn generate(generator: Generator) -> Vec<csv::StringRecord> {
+        let mut output = vec![];
+        generator.generate(&mut output).unwrap();
+        csv::Reader::from_reader(output.as_slice())
+            .records()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn should_parse_weights() {
+        assert_eq!("60, 30,5,3,2".parse::<Weights>().unwrap(), Weights([60, 30, 5, 3, 2]));
+        assert!("1,2".parse::<Weights>().is_err());
+        assert_eq!(
+            "0,0,0,0,0".parse::<Weights>().unwrap_err().to_string(),
+            "At least one weight must be positive"
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_generator() {
+        let error = |generator: Generator| {
+            let mut output = vec![];
+            let e = generator.generate(&mut output).unwrap_err().to_string();
+            assert!(output.is_empty());
+            e
+        };
+        assert_eq!(
+            error(Generator { clients: 0, rows: 3, ..Default::default() }),
+            "Clients must be positive"
+        );
+        assert_eq!(
+            error(Generator { weights: Weights([0; 5]), ..Default::default() }),
+            "At least one weight must be positive"
+        );
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(
+            error(Generator { rows: u32::MAX as usize + 1, ..Default::default() }),
+            "Too many rows 4294967296, at most 4294967295"
+        );
+    }
+
+    #[test]
+    fn should_generate_only_types_with_weight() {
+        let rows = generate(Generator {
+            rows: 100,
+            weights: Weights([1, 0, 0, 0, 0]),
+            ..Default::default()
+        });
+        assert_eq!(rows.len(), 100);
+        assert!(rows.iter().all(|r| &r[0] == "deposit"));
+    }
+
+    #[test]
+    fn should_generate_disputes_of_existing_deposits() {
+        let rows = generate(Generator {
+            rows: 1_000,
+            weights: Weights([10, 0, 5, 0, 0]),
+            valid_disputes: true,
+            ..Default::default()
+        });
+        let mut deposits = HashSet::new();
+        for r in &rows {
+            match &r[0] {
+                "deposit" => {
+                    deposits.insert((r[1].to_string(), r[2].to_string()));
+                }
+                _ => {
+                    // disputes before the first deposit cannot reference any
+                    if !deposits.is_empty() {
+                        assert!(deposits.contains(&(r[1].to_string(), r[2].to_string())));
+                    }
+                }
+            }
+        }
+    }
+}
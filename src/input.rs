@@ -0,0 +1,389 @@
+use anyhow::{bail, ensure};
+use sha2::{Digest, Sha256};
+use std::io::{self, BufRead, Read, Write};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Wait before the first retry of a failed IO operation, doubled for every next one.
+const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Minimal time between two progress reports.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Frames of the spinner shown when the input length is unknown.
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum InputFormat {
+    #[default]
+    Csv,
+    /// Single JSON array of txs, it is buffered in memory as a whole.
+    Json,
+    /// One JSON tx per line, streamed.
+    Ndjson,
+    /// Detected from the first non-whitespace byte of the input.
+    Auto,
+}
+
+impl InputFormat {
+    pub(crate) const VARIANTS: &'static [&'static str] = &["csv", "json", "ndjson", "auto"];
+
+    /// Peeks at the first non-whitespace byte: `[` means JSON, `{` means NDJSON, anything else CSV.
+    /// Leading whitespace is consumed, nothing else is.
+    pub(crate) fn sniff<R: BufRead>(reader: &mut R) -> anyhow::Result<Self> {
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(InputFormat::Csv);
+            }
+            let whitespace = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+            if whitespace < buf.len() {
+                let format = match buf[whitespace] {
+                    b'[' => InputFormat::Json,
+                    b'{' => InputFormat::Ndjson,
+                    _ => InputFormat::Csv,
+                };
+                reader.consume(whitespace);
+                return Ok(format);
+            }
+            reader.consume(whitespace);
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "csv" => InputFormat::Csv,
+            "json" => InputFormat::Json,
+            "ndjson" => InputFormat::Ndjson,
+            "auto" => InputFormat::Auto,
+            _ => bail!("Unknown input format {}", s),
+        })
+    }
+}
+
+/// Byte order mark some exports start with, it is not a part of the first column name.
+pub(crate) const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Parses CSV delimiter, which has to be a single ASCII character, e.g. `;`.
+pub(crate) fn parse_delimiter(s: &str) -> anyhow::Result<u8> {
+    ensure!(s.len() == 1 && s.is_ascii(), "Delimiter {} is not a single ASCII character", s);
+    Ok(s.as_bytes()[0])
+}
+
+/// Reader computing SHA-256 of everything read through it.
+pub(crate) struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Reads the rest of the input and returns its hex encoded hash.
+    pub(crate) fn finish(mut self) -> io::Result<String> {
+        io::copy(&mut self, &mut io::sink())?;
+        Ok(self
+            .hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Replaces a line longer than the limit.
+/// It is not a valid row in any format, so it is counted and reported as a row which cannot
+/// be parsed, and as a header it is missing all the columns.
+pub(crate) const LINE_TOO_LONG: &[u8] = b"line too long\n";
+
+/// Reader replacing lines longer than the limit (including the line break)
+/// with [`LINE_TOO_LONG`], so they are skipped without being buffered as a whole.
+pub(crate) struct LineLimitReader<R> {
+    inner: R,
+    max: usize,
+    line: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: BufRead> LineLimitReader<R> {
+    pub(crate) fn new(inner: R, max: usize) -> Self {
+        LineLimitReader {
+            inner,
+            max,
+            line: vec![],
+            pos: 0,
+        }
+    }
+
+    /// Reads next line, returns false at the end of input.
+    fn next_line(&mut self) -> io::Result<bool> {
+        self.line.clear();
+        self.pos = 0;
+        let limit = self.max as u64 + 1;
+        if self.inner.by_ref().take(limit).read_until(b'\n', &mut self.line)? == 0 {
+            return Ok(false);
+        }
+        if self.line.len() > self.max {
+            if self.line.last() != Some(&b'\n') {
+                self.skip_line()?;
+            }
+            self.line.clear();
+            self.line.extend_from_slice(LINE_TOO_LONG);
+        }
+        Ok(true)
+    }
+
+    fn skip_line(&mut self) -> io::Result<()> {
+        loop {
+            let buf = self.inner.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(());
+            }
+            match buf.iter().position(|b| *b == b'\n') {
+                Some(i) => {
+                    self.inner.consume(i + 1);
+                    return Ok(());
+                }
+                None => {
+                    let len = buf.len();
+                    self.inner.consume(len);
+                }
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Read for LineLimitReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.line.len() && !self.next_line()? {
+            return Ok(0);
+        }
+        let n = buf.len().min(self.line.len() - self.pos);
+        buf[..n].copy_from_slice(&self.line[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Calls `f` until it succeeds, at most `retries` more times after the first failure.
+pub(crate) fn retry<T>(retries: u32, mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut backoff = RETRY_BACKOFF;
+    for _ in 0..retries {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(_) => {
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+        }
+    }
+    f()
+}
+
+/// Reader retrying failed reads, see [`retry`].
+pub(crate) struct RetryReader<R> {
+    inner: R,
+    retries: u32,
+}
+
+impl<R: Read> RetryReader<R> {
+    pub(crate) fn new(inner: R, retries: u32) -> Self {
+        RetryReader { inner, retries }
+    }
+}
+
+impl<R: Read> Read for RetryReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        retry(self.retries, || self.inner.read(buf))
+    }
+}
+
+/// Reader reporting how much of the input has been read, at most once per interval:
+/// as percentage of the length if it is known, otherwise as a spinner with number of lines.
+pub(crate) struct ProgressReader<R, W> {
+    inner: R,
+    out: W,
+    len: Option<u64>,
+    read: u64,
+    lines: u64,
+    interval: Duration,
+    last: Option<Instant>,
+    ticks: usize,
+    done: bool,
+}
+
+impl<R: Read, W: Write> ProgressReader<R, W> {
+    pub(crate) fn new(inner: R, len: Option<u64>, out: W) -> Self {
+        ProgressReader {
+            inner,
+            out,
+            len,
+            read: 0,
+            lines: 0,
+            interval: PROGRESS_INTERVAL,
+            last: None,
+            ticks: 0,
+            done: false,
+        }
+    }
+
+    fn report(&mut self) -> io::Result<()> {
+        self.ticks += 1;
+        match self.len {
+            Some(len) => {
+                let percent = (self.read * 100).checked_div(len).unwrap_or(100);
+                write!(self.out, "\r{}%", percent)
+            }
+            None => {
+                let frame = SPINNER[self.ticks % SPINNER.len()];
+                write!(self.out, "\r{} {} lines", frame, self.lines)
+            }
+        }
+    }
+}
+
+impl<R: Read, W: Write> Read for ProgressReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        self.lines += buf[..n].iter().filter(|b| **b == b'\n').count() as u64;
+        // progress is only informative, so failing to report it does not fail the read
+        if n == 0 && !self.done {
+            self.done = true;
+            let _ = self.report().and_then(|_| writeln!(self.out));
+        } else if n > 0 && self.last.is_none_or(|last| last.elapsed() >= self.interval) {
+            self.last = Some(Instant::now());
+            let _ = self.report();
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sniff(input: &str) -> InputFormat {
+        InputFormat::sniff(&mut input.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn should_sniff_formats() {
+        assert_eq!(sniff("type, client, tx, amount\n"), InputFormat::Csv);
+        assert_eq!(sniff("  \n[{\"type\": \"deposit\"}]"), InputFormat::Json);
+        assert_eq!(sniff("\n{\"type\": \"deposit\"}\n"), InputFormat::Ndjson);
+        assert_eq!(sniff(""), InputFormat::Csv);
+    }
+
+    #[test]
+    fn should_hash_whole_input() -> anyhow::Result<()> {
+        let mut reader = HashingReader::new("abc".as_bytes());
+        let mut first = [0; 1];
+        reader.read_exact(&mut first)?;
+        assert_eq!(
+            reader.finish()?,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_replace_too_long_lines() -> anyhow::Result<()> {
+        let (x, y, z) = ("x".repeat(10_000), "y".repeat(5), "z".repeat(6));
+        let input = format!("a,b\n{}\nc,d\n{}\n1234\n{}", x, y, z);
+        let mut output = String::new();
+        LineLimitReader::new(input.as_bytes(), 5).read_to_string(&mut output)?;
+        assert_eq!(output, "a,b\nline too long\nc,d\nline too long\n1234\nline too long\n");
+        Ok(())
+    }
+
+    /// Fails every other read.
+    struct FlakyReader<'a> {
+        input: &'a [u8],
+        fail: bool,
+    }
+
+    impl Read for FlakyReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.fail = !self.fail;
+            match self.fail {
+                true => Err(io::ErrorKind::TimedOut.into()),
+                false => self.input.read(&mut buf[..1]),
+            }
+        }
+    }
+
+    #[test]
+    fn should_retry_failed_reads() -> anyhow::Result<()> {
+        let mut output = String::new();
+        let flaky = FlakyReader {
+            input: b"abc",
+            fail: false,
+        };
+        RetryReader::new(flaky, 1).read_to_string(&mut output)?;
+        assert_eq!(output, "abc");
+
+        let flaky = FlakyReader {
+            input: b"abc",
+            fail: false,
+        };
+        let e = RetryReader::new(flaky, 0).read_to_string(&mut output).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_progress() -> anyhow::Result<()> {
+        let mut out = vec![];
+        let mut reader = ProgressReader::new("a\nb\n".as_bytes(), Some(4), &mut out);
+        reader.interval = Duration::ZERO;
+        reader.read_exact(&mut [0; 2])?;
+        reader.read_to_end(&mut vec![])?;
+        assert_eq!(String::from_utf8(out)?, "\r50%\r100%\r100%\n");
+
+        let mut out = vec![];
+        let mut reader = ProgressReader::new("a\nb\n".as_bytes(), None, &mut out);
+        reader.read_to_end(&mut vec![])?;
+        // reported once within the interval and at the end
+        assert_eq!(String::from_utf8(out)?, "\r/ 2 lines\r- 2 lines\n");
+        Ok(())
+    }
+
+    #[test]
+    fn should_parse_only_ascii_delimiter() -> anyhow::Result<()> {
+        assert_eq!(parse_delimiter(";")?, b';');
+        assert_eq!(parse_delimiter("\t")?, b'\t');
+        assert_eq!(
+            parse_delimiter("§").unwrap_err().to_string(),
+            "Delimiter § is not a single ASCII character"
+        );
+        assert!(parse_delimiter(";;").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_parse_unknown_format() {
+        assert_eq!(
+            "xml".parse::<InputFormat>().unwrap_err().to_string(),
+            "Unknown input format xml"
+        );
+    }
+}
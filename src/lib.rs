@@ -0,0 +1,34 @@
+//! Engine applying txs to client accounts, the `tx_fun` binary is a thin wrapper over [`cli`].
+//!
+//! ```
+//! use tx_fun::engine::{Config, Engine};
+//! use tx_fun::tx::{Tx, TxType};
+//!
+//! let mut engine = Engine::new(Config::default())?;
+//! let deposit = Tx {
+//!     tx_type: TxType::Deposit { amount: 5.into() },
+//!     client_id: 1,
+//!     tx_id: 1,
+//!     currency: None,
+//! };
+//! assert_eq!(engine.apply(deposit)?.available, 5.into());
+//! let mut clients = engine.into_clients();
+//! assert_eq!(clients.get_mut(&1).unwrap().undo_last()?, 1);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+mod amount;
+pub mod cli;
+pub mod client;
+pub mod engine;
+mod fast_csv;
+mod gen;
+mod input;
+mod output;
+#[cfg(feature = "proto")]
+pub mod proto;
+mod settle;
+mod stats;
+pub mod tx;
+#[cfg(feature = "watch")]
+mod watch;
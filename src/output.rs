@@ -0,0 +1,201 @@
+use anyhow::{bail, ensure};
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// What and how is written about a client to the output.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ViewOptions {
+    pub(crate) locked_format: LockedFormat,
+    /// Adds `version` column with the number of txs applied to the client.
+    pub(crate) version: bool,
+    /// Omits `client` column, e.g. when client id is a key of a map.
+    pub(crate) without_id: bool,
+    /// Amounts are written as integers scaled by [`crate::amount::SCALE`].
+    pub(crate) raw_amounts: bool,
+    /// Adds `lock_reason` column, empty for unlocked account.
+    pub(crate) lock_reason: bool,
+    /// Decimal places amounts are rounded to, [`crate::amount::PRECISION`] if not given.
+    pub(crate) precision: Option<u32>,
+    /// Adds `deposit_count` and `withdrawal_count` columns.
+    pub(crate) counts: bool,
+    /// Adds `currency` column, empty for client without any.
+    pub(crate) currency: bool,
+    /// Adds `worst_case_total` column with the total left if all disputes were charged back.
+    pub(crate) risk: bool,
+}
+
+/// Greatest precision of the output.
+pub(crate) const MAX_PRECISION: u32 = 10;
+
+/// Parses number of decimal places of the output, up to the [`MAX_PRECISION`].
+pub(crate) fn parse_precision(s: &str) -> anyhow::Result<u32> {
+    let precision = s.parse()?;
+    ensure!(
+        precision <= MAX_PRECISION,
+        "Precision {} out of range 0..={}",
+        precision,
+        MAX_PRECISION
+    );
+    Ok(precision)
+}
+
+/// Representation of the `locked` column.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum LockedFormat {
+    /// `true`/`false`
+    #[default]
+    Bool,
+    /// `1`/`0`
+    Int,
+    /// `Y`/`N`
+    Yn,
+}
+
+impl LockedFormat {
+    pub(crate) const VARIANTS: &'static [&'static str] = &["bool", "int", "yn"];
+}
+
+impl FromStr for LockedFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "bool" => LockedFormat::Bool,
+            "int" => LockedFormat::Int,
+            "yn" => LockedFormat::Yn,
+            _ => bail!("Unknown locked format {}", s),
+        })
+    }
+}
+
+/// Format of the clients' output.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum OutputFormat {
+    #[default]
+    Csv,
+    /// Single JSON array of clients.
+    Json,
+    /// Length-delimited protobuf `ClientBalance` messages.
+    #[cfg(feature = "proto")]
+    Proto,
+}
+
+impl OutputFormat {
+    #[cfg(not(feature = "proto"))]
+    pub(crate) const VARIANTS: &'static [&'static str] = &["csv", "json"];
+    #[cfg(feature = "proto")]
+    pub(crate) const VARIANTS: &'static [&'static str] = &["csv", "json", "proto"];
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "csv" => OutputFormat::Csv,
+            "json" => OutputFormat::Json,
+            #[cfg(feature = "proto")]
+            "proto" => OutputFormat::Proto,
+            _ => bail!("Unknown output format {}", s),
+        })
+    }
+}
+
+/// Primary key clients are sorted by in the output, ties are broken by client id.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum SortKey {
+    #[default]
+    Id,
+    Total,
+    Available,
+    /// Order in which clients first appeared in the input.
+    FirstSeen,
+    /// Arbitrary order, which might differ between runs, but is the fastest one.
+    Unsorted,
+}
+
+impl SortKey {
+    pub(crate) const VARIANTS: &'static [&'static str] = &["id", "total", "available", "first-seen", "none"];
+}
+
+impl FromStr for SortKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "id" => SortKey::Id,
+            "total" => SortKey::Total,
+            "available" => SortKey::Available,
+            "first-seen" => SortKey::FirstSeen,
+            "none" => SortKey::Unsorted,
+            _ => bail!("Unknown sort key {}", s),
+        })
+    }
+}
+
+/// Rewrites CSV with a header as a table with aligned columns and a separator under the header.
+/// Columns are right-justified, except `locked` which is not a number.
+pub(crate) fn write_table<R: Read, W: Write>(csv: R, mut writer: W) -> anyhow::Result<()> {
+    let mut rdr = csv::ReaderBuilder::new().has_headers(false).from_reader(csv);
+    let rows = rdr.records().collect::<Result<Vec<_>, _>>()?;
+    let Some(header) = rows.first() else {
+        return Ok(());
+    };
+    let mut widths = vec![0; header.len()];
+    for row in &rows {
+        for (width, field) in widths.iter_mut().zip(row) {
+            *width = (*width).max(field.chars().count());
+        }
+    }
+    let left: Vec<bool> = header.iter().map(|name| name == "locked").collect();
+    let separator: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+    for (i, row) in rows.iter().enumerate() {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .zip(&left)
+            .map(|((field, width), left)| match left {
+                true => format!("{:<width$}", field, width = width),
+                false => format!("{:>width$}", field, width = width),
+            })
+            .collect();
+        writeln!(writer, "{}", line.join("  ").trim_end())?;
+        if i == 0 {
+            writeln!(writer, "{}", separator.join("  "))?;
+        }
+    }
+    Ok(writer.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_precision_in_range() {
+        assert_eq!(parse_precision("0").unwrap(), 0);
+        assert_eq!(parse_precision("10").unwrap(), 10);
+        assert_eq!(
+            parse_precision("11").unwrap_err().to_string(),
+            "Precision 11 out of range 0..=10"
+        );
+        assert!(parse_precision("-1").is_err());
+    }
+
+    #[test]
+    fn should_align_table_columns() -> anyhow::Result<()> {
+        let csv = "client,available,held,total,locked\n\
+                   1,1.5,0.0,1.5,false\n\
+                   12,1000.1234,25.0,1025.1234,true\n";
+        let mut output = vec![];
+        write_table(csv.as_bytes(), &mut output)?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            "client  available  held      total  locked\n\
+             ------  ---------  ----  ---------  ------\n     \
+                  1        1.5   0.0        1.5  false\n    \
+                 12  1000.1234  25.0  1025.1234  true\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+//! Protobuf messages, defined by hand so no `protoc` is needed to build.
+
+use crate::amount;
+use crate::client::Client;
+
+/// Balance of a client, amounts are integers in units of 1/`scale`, so they are not rounded
+/// on the way.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ClientBalance {
+    #[prost(uint32, tag = "1")]
+    pub client: u32,
+    #[prost(sint64, tag = "2")]
+    pub available: i64,
+    #[prost(sint64, tag = "3")]
+    pub held: i64,
+    #[prost(sint64, tag = "4")]
+    pub total: i64,
+    #[prost(uint64, tag = "5")]
+    pub scale: u64,
+    #[prost(bool, tag = "6")]
+    pub locked: bool,
+}
+
+impl Client {
+    pub fn to_proto(&self) -> anyhow::Result<ClientBalance> {
+        Ok(ClientBalance {
+            client: self.client_id.into(),
+            available: amount::to_scaled(self.available).try_into()?,
+            held: amount::to_scaled(self.held).try_into()?,
+            total: amount::to_scaled(self.total).try_into()?,
+            scale: amount::SCALE.try_into()?,
+            locked: self.locked_or_frozen(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+    use rust_decimal::{Decimal, prelude::FromPrimitive};
+
+    #[test]
+    fn should_round_trip_client_balance() -> anyhow::Result<()> {
+        let mut client = Client::create(7);
+        client.deposit(1, Decimal::from_f64(1.2345).unwrap())?;
+        client.deposit(2, 2.into())?;
+        client.dispute(&2)?;
+        let balance = client.to_proto()?;
+        let decoded = ClientBalance::decode(balance.encode_to_vec().as_slice())?;
+        assert_eq!(decoded, balance);
+        assert_eq!(
+            decoded,
+            ClientBalance {
+                client: 7,
+                available: 12345,
+                held: 20000,
+                total: 32345,
+                scale: 10000,
+                locked: false,
+            }
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::bail;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::client::DrainAction;
+
+/// How funds held by deposits still being disputed are settled at the end of processing.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SettlePolicy {
+    AutoResolve,
+    AutoChargeback,
+    Hold,
+}
+
+impl SettlePolicy {
+    pub(crate) const VARIANTS: &'static [&'static str] = &["auto-resolve", "auto-chargeback", "hold"];
+
+    pub(crate) fn action(&self) -> Option<DrainAction> {
+        match self {
+            SettlePolicy::AutoResolve => Some(DrainAction::Resolve),
+            SettlePolicy::AutoChargeback => Some(DrainAction::Chargeback),
+            SettlePolicy::Hold => None,
+        }
+    }
+}
+
+impl FromStr for SettlePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "auto-resolve" => SettlePolicy::AutoResolve,
+            "auto-chargeback" => SettlePolicy::AutoChargeback,
+            "hold" => SettlePolicy::Hold,
+            _ => bail!("Unknown settle policy {}", s),
+        })
+    }
+}
+
+/// Funds of the client settled according to the policy, for `Hold` it is the amount kept held.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Settlement {
+    pub(crate) client_id: u16,
+    pub(crate) policy: SettlePolicy,
+    pub(crate) amount: Decimal,
+}
+
+#[derive(Deserialize)]
+struct Override {
+    client: u16,
+    policy: SettlePolicy,
+}
+
+/// Reads per client policies from CSV with `client` and `policy` columns.
+pub(crate) fn read_overrides(path: &Path) -> anyhow::Result<HashMap<u16, SettlePolicy>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)?;
+    let mut overrides = HashMap::new();
+    for result in rdr.deserialize() {
+        let Override { client, policy } = result?;
+        overrides.insert(client, policy);
+    }
+    Ok(overrides)
+}
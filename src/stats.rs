@@ -0,0 +1,108 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::tx::TxType;
+
+/// Accumulators of the processed txs.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Stats {
+    pub(crate) deposited: Decimal,
+    pub(crate) withdrawn: Decimal,
+    pub(crate) chargedback: Decimal,
+    /// Change of totals by deltas and adjustments.
+    pub(crate) adjusted: Decimal,
+    /// Withdrawals returned to the clients, held while disputed and available once
+    /// charged back, so unlike `chargedback` it adds to the totals.
+    pub(crate) reversed: Decimal,
+    /// Rows which could not be parsed or processed.
+    pub(crate) skipped: u64,
+    /// Rows applied by an earlier run according to the checkpoint, so not applied again.
+    pub(crate) checkpointed: u64,
+    /// All rows read.
+    pub(crate) rows: u64,
+    /// Funds held by all clients.
+    pub(crate) held: Decimal,
+    /// Rows read by tx type, including the skipped ones.
+    pub(crate) by_type: BTreeMap<&'static str, u64>,
+}
+
+/// Outcome of the whole run, for pipelines.
+#[derive(Debug, Serialize)]
+pub(crate) struct RunSummary {
+    pub(crate) rows: u64,
+    /// Rows which could not be parsed or processed.
+    pub(crate) errors: u64,
+    pub(crate) checkpointed: u64,
+    pub(crate) clients: usize,
+    pub(crate) locked: usize,
+    pub(crate) deposited: Decimal,
+    pub(crate) withdrawn: Decimal,
+    pub(crate) chargedback: Decimal,
+    pub(crate) held: Decimal,
+}
+
+impl Stats {
+    /// Counts a row read, whether it is processed or not.
+    pub(crate) fn count(&mut self, tx_type: &TxType) {
+        *self.by_type.entry(tx_type.name()).or_insert(0) += 1;
+    }
+
+    /// Records a processed tx with the amount it affected.
+    pub(crate) fn record(&mut self, tx_type: &TxType, amount: Decimal) {
+        match tx_type {
+            TxType::Deposit { .. } => self.deposited += amount,
+            TxType::Withdrawal { .. } => self.withdrawn += amount,
+            TxType::Dispute { .. } => self.held += amount,
+            TxType::Resolve => self.held -= amount,
+            TxType::Chargeback => {
+                self.chargedback += amount;
+                self.held -= amount;
+            }
+            TxType::Freeze | TxType::Unfreeze => {}
+            TxType::Delta { held_delta, .. } => {
+                self.adjusted += amount;
+                self.held += held_delta;
+            }
+            TxType::Adjustment { .. } => self.adjusted += amount,
+        }
+    }
+
+    /// Records a processed dispute, resolve or chargeback of a withdrawal,
+    /// its chargeback keeps the amount returned by the dispute.
+    pub(crate) fn record_withdrawal_dispute(&mut self, tx_type: &TxType, amount: Decimal) {
+        match tx_type {
+            TxType::Dispute { .. } => {
+                self.reversed += amount;
+                self.held += amount;
+            }
+            TxType::Resolve => {
+                self.reversed -= amount;
+                self.held -= amount;
+            }
+            TxType::Chargeback => self.held -= amount,
+            _ => self.record(tx_type, amount),
+        }
+    }
+
+    /// Adds up stats of txs processed separately, e.g. by another worker.
+    pub(crate) fn merge(&mut self, other: &Stats) {
+        self.deposited += other.deposited;
+        self.withdrawn += other.withdrawn;
+        self.chargedback += other.chargedback;
+        self.adjusted += other.adjusted;
+        self.reversed += other.reversed;
+        self.skipped += other.skipped;
+        self.checkpointed += other.checkpointed;
+        self.rows += other.rows;
+        self.held += other.held;
+        for (name, count) in &other.by_type {
+            *self.by_type.entry(name).or_insert(0) += count;
+        }
+    }
+
+    /// Expected change of the sum of all clients' totals.
+    pub(crate) fn net(&self) -> Decimal {
+        self.deposited - self.withdrawn - self.chargedback + self.adjusted + self.reversed
+    }
+}
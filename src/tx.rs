@@ -1,33 +1,349 @@
-use anyhow::bail;
-use rust_decimal::Decimal;
-use serde::Deserialize;
-use std::collections::HashMap;
+use anyhow::{anyhow, bail, ensure};
+use rust_decimal::{Decimal, prelude::Zero};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
 
+use crate::amount;
 use crate::client::Client;
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-pub(crate) enum TxType {
-    Deposit { amount: Decimal },
-    Withdrawal { amount: Decimal },
-    Dispute,
+#[derive(Clone, Debug)]
+pub enum TxType {
+    Deposit {
+        amount: Decimal,
+    },
+    Withdrawal {
+        amount: Decimal,
+    },
+    Dispute {
+        /// Part of the deposit disputed, or used to find the deposit when disputing by amount.
+        amount: Option<Decimal>,
+    },
     Resolve,
     Chargeback,
+    /// Administrative lock of the account, independent of chargebacks.
+    Freeze,
+    Unfreeze,
+    /// Administrative adjustment of the funds by signed deltas, e.g. after reconciliation.
+    Delta {
+        available_delta: Decimal,
+        held_delta: Decimal,
+        total_delta: Decimal,
+    },
+    /// Manual correction of available and total funds by signed amount, it cannot be disputed.
+    Adjustment {
+        amount: Decimal,
+    },
+}
+
+impl TxType {
+    /// One tx of every type.
+    pub(crate) fn all() -> [TxType; 9] {
+        [
+            TxType::Deposit { amount: Decimal::ZERO },
+            TxType::Withdrawal { amount: Decimal::ZERO },
+            TxType::Dispute { amount: None },
+            TxType::Resolve,
+            TxType::Chargeback,
+            TxType::Freeze,
+            TxType::Unfreeze,
+            TxType::Delta {
+                available_delta: Decimal::ZERO,
+                held_delta: Decimal::ZERO,
+                total_delta: Decimal::ZERO,
+            },
+            TxType::Adjustment { amount: Decimal::ZERO },
+        ]
+    }
+
+    /// Value of the `type` column.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            TxType::Deposit { .. } => "deposit",
+            TxType::Withdrawal { .. } => "withdrawal",
+            TxType::Dispute { .. } => "dispute",
+            TxType::Resolve => "resolve",
+            TxType::Chargeback => "chargeback",
+            TxType::Freeze => "freeze",
+            TxType::Unfreeze => "unfreeze",
+            TxType::Delta { .. } => "delta",
+            TxType::Adjustment { .. } => "adjustment",
+        }
+    }
+
+    /// Whether the tx refers to a deposit or withdrawal by its tx id.
+    pub(crate) fn refers_to_tx(&self) -> bool {
+        matches!(self, TxType::Dispute { .. } | TxType::Resolve | TxType::Chargeback)
+    }
+
+    pub(crate) fn has_amount(&self) -> bool {
+        matches!(self, TxType::Deposit { .. } | TxType::Withdrawal { .. })
+    }
+
+    /// Whether the tx type requires the `amount` column, unlike disputes for which it is optional.
+    pub(crate) fn requires_amount(&self) -> bool {
+        self.has_amount() || matches!(self, TxType::Adjustment { .. })
+    }
+
+    /// Columns required by the tx type on top of the common ones.
+    pub(crate) fn extra_columns(&self) -> &'static [&'static str] {
+        match self {
+            TxType::Delta { .. } => &["available_delta", "held_delta", "total_delta"],
+            _ => &[],
+        }
+    }
+
+    /// Flag required to accept the tx type.
+    pub(crate) fn required_flag(&self) -> Option<&'static str> {
+        match self {
+            TxType::Freeze | TxType::Unfreeze => Some("--allow-admin-freeze"),
+            TxType::Delta { .. } => Some("--allow-delta"),
+            TxType::Adjustment { .. } => Some("--allow-adjustment"),
+            _ => None,
+        }
+    }
+}
+
+/// Columns every CSV input has to have, in any order.
+pub(crate) const COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Fails if any of the [`COLUMNS`] is missing in the header, e.g. because of a typo,
+/// as every row would be skipped then. Empty input without any header is fine.
+pub(crate) fn ensure_columns<'a>(header: impl IntoIterator<Item = &'a str>) -> anyhow::Result<()> {
+    let header: Vec<&str> = header.into_iter().collect();
+    let missing: Vec<&str> = COLUMNS.into_iter().filter(|c| !header.contains(c)).collect();
+    ensure!(
+        header.is_empty() || missing.is_empty(),
+        "Missing columns {} in the header {}",
+        missing.join(", "),
+        header.join(","),
+    );
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Schema {
+    columns: [&'static str; 4],
+    types: Vec<TypeSchema>,
+}
+
+#[derive(Serialize)]
+struct TypeSchema {
+    r#type: &'static str,
+    amount: bool,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    extra_columns: &'static [&'static str],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    requires: Option<&'static str>,
+}
+
+/// Writes the expected input format as JSON.
+pub(crate) fn dump_schema<W: Write>(mut writer: W) -> anyhow::Result<()> {
+    let schema = Schema {
+        columns: COLUMNS,
+        types: TxType::all()
+            .iter()
+            .map(|t| TypeSchema {
+                r#type: t.name(),
+                amount: t.requires_amount(),
+                extra_columns: t.extra_columns(),
+                requires: t.required_flag(),
+            })
+            .collect(),
+    };
+    serde_json::to_writer_pretty(&mut writer, &schema)?;
+    Ok(writeln!(writer)?)
+}
+
+/// Counts values of the `type` column of CSV input which are not known
+/// or not allowed by the policy, e.g. typos like `withdrawl`. Other columns are not looked at.
+pub(crate) fn unknown_types<R: Read>(
+    reader: R,
+    policy: &Policy,
+    delimiter: u8,
+) -> anyhow::Result<BTreeMap<String, u64>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_reader(reader);
+    let column = rdr
+        .headers()?
+        .iter()
+        .position(|h| h == "type")
+        .ok_or_else(|| anyhow!("Missing type column"))?;
+    let known: Vec<&str> = TxType::all()
+        .iter()
+        .filter(|t| match t {
+            TxType::Freeze | TxType::Unfreeze => policy.allow_admin_freeze,
+            TxType::Delta { .. } => policy.allow_delta,
+            TxType::Adjustment { .. } => policy.allow_adjustment,
+            _ => true,
+        })
+        .map(|t| t.name())
+        .collect();
+    let mut unknown = BTreeMap::new();
+    for record in rdr.records() {
+        let record = record?;
+        if let Some(name) = record.get(column).filter(|name| !known.contains(name)) {
+            *unknown.entry(name.to_string()).or_insert(0) += 1;
+        }
+    }
+    Ok(unknown)
 }
 
 #[derive(Clone, Debug, Deserialize)]
-pub(crate) struct Tx {
-    #[serde(flatten)]
-    pub(crate) tx_type: TxType,
-    #[serde(rename = "client")]
-    pub(crate) client_id: u16,
-    #[serde(rename = "tx")]
-    pub(crate) tx_id: u32,
+#[serde(try_from = "TxRow")]
+pub struct Tx {
+    pub tx_type: TxType,
+    pub client_id: u16,
+    pub tx_id: u32,
+    /// ISO 4217 code, the client's currency is fixed by the first tx having it.
+    pub currency: Option<String>,
+}
+
+/// Input row with every column optional but the common ones, so a row missing the columns
+/// its type requires is reported as such, not as a generic parse error.
+/// Amounts are kept as text until [`TxRow::into_tx`] knows whether fractions are allowed.
+#[derive(Deserialize)]
+pub(crate) struct TxRow {
+    r#type: String,
+    client: u16,
+    tx: u32,
+    #[serde(default, deserialize_with = "amount::deserialize_raw")]
+    amount: Option<String>,
+    #[serde(default, deserialize_with = "amount::deserialize_raw")]
+    available_delta: Option<String>,
+    #[serde(default, deserialize_with = "amount::deserialize_raw")]
+    held_delta: Option<String>,
+    #[serde(default, deserialize_with = "amount::deserialize_raw")]
+    total_delta: Option<String>,
+    #[serde(default)]
+    currency: Option<String>,
+}
+
+/// Fails if the column required by the tx type has no value.
+pub(crate) fn required<T>(
+    value: Option<T>,
+    tx_type: &str,
+    tx_id: u32,
+    column: &str,
+) -> anyhow::Result<T> {
+    let mut name = tx_type.to_string();
+    name[..1].make_ascii_uppercase();
+    value.ok_or_else(|| anyhow!("{} row {} missing {}", name, tx_id, column))
+}
+
+impl TxRow {
+    /// Converts the row into a tx, parsing its amounts as fractions too if allowed.
+    pub(crate) fn into_tx(self, allow_fractions: bool) -> anyhow::Result<Tx> {
+        let row = self;
+        let parse = |value: &Option<String>| {
+            value
+                .as_deref()
+                .map(|value| amount::parse(value, allow_fractions))
+                .transpose()
+        };
+        let value = parse(&row.amount)?;
+        let amount = || required(value, &row.r#type, row.tx, "amount");
+        let delta = |value, column| required(parse(value)?, &row.r#type, row.tx, column);
+        let tx_type = match row.r#type.as_str() {
+            "deposit" => TxType::Deposit { amount: amount()? },
+            "withdrawal" => TxType::Withdrawal { amount: amount()? },
+            "dispute" => TxType::Dispute { amount: value },
+            "resolve" => TxType::Resolve,
+            "chargeback" => TxType::Chargeback,
+            "freeze" => TxType::Freeze,
+            "unfreeze" => TxType::Unfreeze,
+            "delta" => TxType::Delta {
+                available_delta: delta(&row.available_delta, "available_delta")?,
+                held_delta: delta(&row.held_delta, "held_delta")?,
+                total_delta: delta(&row.total_delta, "total_delta")?,
+            },
+            "adjustment" => TxType::Adjustment { amount: amount()? },
+            other => bail!("Unknown tx type {}", other),
+        };
+        Ok(Tx {
+            tx_type,
+            client_id: row.client,
+            tx_id: row.tx,
+            currency: row.currency,
+        })
+    }
+}
+
+impl TryFrom<TxRow> for Tx {
+    type Error = anyhow::Error;
+
+    /// Converts the row with fractions not allowed, see [`TxRow::into_tx`].
+    fn try_from(row: TxRow) -> anyhow::Result<Tx> {
+        row.into_tx(false)
+    }
+}
+
+/// Optional rules applied to txs on top of the default ones.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Policy {
+    /// Withdrawals leaving available funds between zero and this threshold are rejected.
+    pub(crate) dust_threshold: Option<Decimal>,
+    /// Freeze and Unfreeze txs are rejected unless allowed.
+    pub(crate) allow_admin_freeze: bool,
+    /// Withdrawal creates the account if it does not exist, even if it fails then.
+    pub(crate) create_on_withdrawal: bool,
+    /// Delta txs are rejected unless allowed.
+    pub(crate) allow_delta: bool,
+    /// Adjustment txs are rejected unless allowed.
+    pub(crate) allow_adjustment: bool,
+    /// Disputes of withdrawals are rejected unless allowed.
+    pub(crate) allow_withdrawal_disputes: bool,
+    /// Resolve and Chargeback of existing, but not disputed deposit do nothing instead of failing.
+    pub(crate) ignore_undisputed: bool,
+    /// Dispute having an amount refers to the most recent undisputed deposit of that amount,
+    /// instead of the one with its tx id.
+    pub(crate) dispute_by_amount: bool,
+    /// Deposit of negative amount is a withdrawal and vice versa,
+    /// otherwise negative amounts are rejected.
+    pub(crate) signed_amounts: bool,
+    /// Dispute having an amount disputes only that part of the deposit, instead of all of it.
+    pub(crate) allow_partial_disputes: bool,
+    /// Deposits and withdrawals above this amount are rejected as likely data-entry errors.
+    pub(crate) max_amount: Option<Decimal>,
 }
 
 impl Tx {
-    pub(crate) fn process(&self, clients: &mut HashMap<u16, Client>) -> anyhow::Result<()> {
-        let client = if let TxType::Deposit { .. } = self.tx_type {
+    /// Turns deposit of negative amount into withdrawal of the opposite one and vice versa,
+    /// if the policy allows signed amounts.
+    pub(crate) fn normalized(self, policy: &Policy) -> Tx {
+        let tx_type = match self.tx_type {
+            TxType::Deposit { amount } if policy.signed_amounts && amount < Decimal::ZERO => {
+                TxType::Withdrawal { amount: -amount }
+            }
+            TxType::Withdrawal { amount } if policy.signed_amounts && amount < Decimal::ZERO => {
+                TxType::Deposit { amount: -amount }
+            }
+            tx_type => tx_type,
+        };
+        Tx { tx_type, ..self }
+    }
+
+    /// Applies the tx to the client it belongs to.
+    /// Returns the amount affected by the tx.
+    pub(crate) fn process(
+        &self,
+        clients: &mut HashMap<u16, Client>,
+        policy: &Policy,
+    ) -> anyhow::Result<Decimal> {
+        if let (TxType::Deposit { amount } | TxType::Withdrawal { amount }, Some(max)) =
+            (&self.tx_type, policy.max_amount)
+        {
+            ensure!(*amount <= max, "Amount exceeds max {}: {}", max, amount);
+        }
+        let creates = match self.tx_type {
+            TxType::Deposit { .. } => true,
+            TxType::Withdrawal { .. } => policy.create_on_withdrawal,
+            _ => false,
+        };
+        let client = if creates {
             clients
                 .entry(self.client_id)
                 .or_insert(Client::create(self.client_id))
@@ -38,12 +354,453 @@ impl Tx {
             }
         };
 
-        match &self.tx_type {
-            TxType::Deposit { amount } => client.deposit(self.tx_id, amount.clone()),
-            TxType::Withdrawal { amount } => client.withdraw(amount.clone()),
-            TxType::Dispute => client.dispute(&self.tx_id),
+        if let Some(currency) = &self.currency {
+            ensure!(
+                currency.len() == 3 && currency.bytes().all(|b| b.is_ascii_uppercase()),
+                "Invalid currency {}",
+                currency
+            );
+            client.ensure_currency(currency)?;
+        }
+        let amount = match &self.tx_type {
+            TxType::Deposit { amount } => client.deposit(self.tx_id, *amount).map(|_| *amount),
+            TxType::Withdrawal { amount } => {
+                let disputable = policy.allow_withdrawal_disputes;
+                match policy.dust_threshold {
+                    Some(threshold) => client.withdraw_leaving_no_dust(
+                        self.tx_id,
+                        *amount,
+                        threshold,
+                        disputable,
+                    ),
+                    None if disputable => client.withdraw_disputable(self.tx_id, *amount),
+                    None => client.withdraw(self.tx_id, *amount),
+                }
+                .map(|_| *amount)
+            }
+            TxType::Dispute {
+                amount: Some(amount),
+            } if policy.dispute_by_amount => client.dispute_by_amount(*amount),
+            TxType::Dispute {
+                amount: Some(amount),
+            } if policy.allow_partial_disputes => client.dispute_part(&self.tx_id, *amount),
+            TxType::Dispute { .. } => client.dispute(&self.tx_id),
+            TxType::Resolve | TxType::Chargeback
+                if policy.ignore_undisputed && client.is_disputed(&self.tx_id) == Some(false) =>
+            {
+                Ok(Decimal::zero())
+            }
             TxType::Resolve => client.resolve(&self.tx_id),
             TxType::Chargeback => client.chargeback(&self.tx_id),
+            TxType::Freeze | TxType::Unfreeze if !policy.allow_admin_freeze => {
+                bail!("Admin freeze is not allowed")
+            }
+            TxType::Freeze => client.freeze().map(|_| Decimal::zero()),
+            TxType::Unfreeze => client.unfreeze().map(|_| Decimal::zero()),
+            TxType::Delta { .. } if !policy.allow_delta => bail!("Delta is not allowed"),
+            TxType::Delta {
+                available_delta,
+                held_delta,
+                total_delta,
+            } => client
+                .apply_delta(*available_delta, *held_delta, *total_delta)
+                .map(|_| *total_delta),
+            TxType::Adjustment { .. } if !policy.allow_adjustment => {
+                bail!("Adjustment is not allowed")
+            }
+            TxType::Adjustment { amount } => client.adjust(*amount).map(|_| *amount),
+        }?;
+        client.version += 1;
+        if client.currency.is_none() {
+            client.currency = self.currency.clone();
+        }
+        Ok(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::discriminant;
+
+    #[test]
+    fn should_count_unknown_types() -> anyhow::Result<()> {
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 1.0\n\
+                   withdrawl, 1, 2, 1.0\n\
+                   freeze, 1, 3,\n\
+                   withdrawl, 1, 4, 1.0\n\
+                   withdrawal, 1, 5, 1.0\n";
+        let unknown = unknown_types(csv.as_bytes(), &Policy::default(), b',')?;
+        assert_eq!(
+            unknown.into_iter().collect::<Vec<_>>(),
+            [("freeze".to_string(), 1), ("withdrawl".to_string(), 2)]
+        );
+        let policy = Policy {
+            allow_admin_freeze: true,
+            ..Default::default()
+        };
+        let unknown = unknown_types(csv.as_bytes(), &policy, b',')?;
+        assert_eq!(unknown.into_iter().collect::<Vec<_>>(), [("withdrawl".to_string(), 2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_deserialize_names_of_all_types() -> anyhow::Result<()> {
+        for tx_type in TxType::all() {
+            let csv = if tx_type.requires_amount() {
+                format!("type,client,tx,amount\n{},1,1,1.0\n", tx_type.name())
+            } else if !tx_type.extra_columns().is_empty() {
+                format!(
+                    "type,client,tx,{}\n{},1,1,1.0,0.0,1.0\n",
+                    tx_type.extra_columns().join(","),
+                    tx_type.name()
+                )
+            } else {
+                format!("type,client,tx,amount\n{},1,1,\n", tx_type.name())
+            };
+            let tx: Tx = csv::Reader::from_reader(csv.as_bytes())
+                .deserialize()
+                .next()
+                .unwrap()?;
+            assert_eq!(discriminant(&tx.tx_type), discriminant(&tx_type));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_adjust_by_signed_amount_when_allowed() -> anyhow::Result<()> {
+        let csv = "type,client,tx,amount\nadjustment,1,2,-1.5\n";
+        let adjustment: Tx = csv::Reader::from_reader(csv.as_bytes())
+            .deserialize()
+            .next()
+            .unwrap()?;
+        let mut clients = HashMap::from([(1, Client::create(1))]);
+        clients.get_mut(&1).unwrap().deposit(1, 5.into())?;
+        assert_eq!(
+            adjustment.process(&mut clients, &Policy::default()).unwrap_err().to_string(),
+            "Adjustment is not allowed"
+        );
+        let policy = Policy {
+            allow_adjustment: true,
+            ..Default::default()
+        };
+        assert_eq!(adjustment.process(&mut clients, &policy)?, Decimal::new(-15, 1));
+        assert_eq!(clients[&1].available, Decimal::new(35, 1));
+        assert_eq!(clients[&1].total, Decimal::new(35, 1));
+        assert_eq!(
+            tx(TxType::Dispute { amount: None }, 2)
+                .process(&mut clients, &policy)
+                .unwrap_err()
+                .to_string(),
+            "Deposit not found 2"
+        );
+        Ok(())
+    }
+
+    fn delta(available: i64, held: i64, total: i64) -> Tx {
+        Tx {
+            tx_type: TxType::Delta {
+                available_delta: available.into(),
+                held_delta: held.into(),
+                total_delta: total.into(),
+            },
+            client_id: 1,
+            tx_id: 2,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn should_apply_delta_when_allowed() -> anyhow::Result<()> {
+        let mut clients = HashMap::from([(1, Client::create(1))]);
+        clients.get_mut(&1).unwrap().deposit(1, 5.into())?;
+        assert_eq!(
+            delta(-2, 1, -1)
+                .process(&mut clients, &Policy::default())
+                .unwrap_err()
+                .to_string(),
+            "Delta is not allowed"
+        );
+        let policy = Policy {
+            allow_delta: true,
+            ..Default::default()
+        };
+        assert_eq!(delta(-2, 1, -1).process(&mut clients, &policy)?, (-1).into());
+        let client = &clients[&1];
+        assert_eq!(client.available, 3.into());
+        assert_eq!(client.held, 1.into());
+        assert_eq!(client.total, 4.into());
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_delta_breaking_invariant() -> anyhow::Result<()> {
+        let mut clients = HashMap::from([(1, Client::create(1))]);
+        clients.get_mut(&1).unwrap().deposit(1, 5.into())?;
+        let policy = Policy {
+            allow_delta: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            delta(-2, 1, 0).process(&mut clients, &policy).unwrap_err().to_string(),
+            "Account 1: Delta would break available + held == total: 3 + 1 != 5"
+        );
+        assert_eq!(clients[&1].available, 5.into());
+        assert_eq!(clients[&1].version, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_delta_leaving_funds_negative() -> anyhow::Result<()> {
+        let mut clients = HashMap::from([(1, Client::create(1))]);
+        clients.get_mut(&1).unwrap().deposit(1, 5.into())?;
+        let policy = Policy {
+            allow_delta: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            delta(-10, 0, -10).process(&mut clients, &policy).unwrap_err().to_string(),
+            "Account 1: Negative funds: available -5, held 0"
+        );
+        assert_eq!(clients[&1].available, 5.into());
+        assert_eq!(clients[&1].total, 5.into());
+        Ok(())
+    }
+
+    fn tx(tx_type: TxType, tx_id: u32) -> Tx {
+        Tx {
+            tx_type,
+            client_id: 1,
+            tx_id,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn should_fail_resolve_and_chargeback_without_dispute() -> anyhow::Result<()> {
+        let mut clients = HashMap::new();
+        let policy = Policy::default();
+        tx(TxType::Deposit { amount: 5.into() }, 1).process(&mut clients, &policy)?;
+        for tx_type in [TxType::Resolve, TxType::Chargeback] {
+            assert_eq!(
+                tx(tx_type, 1).process(&mut clients, &policy).unwrap_err().to_string(),
+                "Deposit in state Ok != Dispute"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_ignore_resolve_and_chargeback_without_dispute_when_lenient() -> anyhow::Result<()> {
+        let mut clients = HashMap::new();
+        let policy = Policy {
+            ignore_undisputed: true,
+            ..Default::default()
+        };
+        tx(TxType::Deposit { amount: 5.into() }, 1).process(&mut clients, &policy)?;
+        assert_eq!(tx(TxType::Resolve, 1).process(&mut clients, &policy)?, 0.into());
+        assert_eq!(tx(TxType::Chargeback, 1).process(&mut clients, &policy)?, 0.into());
+        assert_eq!(
+            tx(TxType::Resolve, 2).process(&mut clients, &policy).unwrap_err().to_string(),
+            "Deposit not found 2"
+        );
+        let client = &clients[&1];
+        assert_eq!(client.available, 5.into());
+        assert!(!client.locked);
+        Ok(())
+    }
+
+    #[test]
+    fn should_dispute_withdrawal_when_allowed() -> anyhow::Result<()> {
+        let mut clients = HashMap::new();
+        let policy = Policy::default();
+        tx(TxType::Deposit { amount: 5.into() }, 1).process(&mut clients, &policy)?;
+        tx(TxType::Withdrawal { amount: 2.into() }, 2).process(&mut clients, &policy)?;
+        // not stored, as it cannot be disputed
+        let dispute = tx(TxType::Dispute { amount: None }, 2);
+        assert_eq!(
+            dispute.process(&mut clients, &policy).unwrap_err().to_string(),
+            "Deposit not found 2"
+        );
+        let policy = Policy {
+            allow_withdrawal_disputes: true,
+            ..Default::default()
+        };
+        tx(TxType::Withdrawal { amount: 1.into() }, 3).process(&mut clients, &policy)?;
+        let dispute = tx(TxType::Dispute { amount: None }, 3);
+        assert_eq!(dispute.process(&mut clients, &policy)?, 1.into());
+        assert_eq!(clients[&1].held, 1.into());
+        assert_eq!(clients[&1].total, 3.into());
+        Ok(())
+    }
+
+    #[test]
+    fn should_dispute_part_of_deposit_when_allowed() -> anyhow::Result<()> {
+        let mut clients = HashMap::new();
+        let policy = Policy::default();
+        tx(TxType::Deposit { amount: 5.into() }, 1).process(&mut clients, &policy)?;
+        let dispute = tx(TxType::Dispute { amount: Some(2.into()) }, 1);
+        // amount is ignored unless partial disputes are allowed
+        assert_eq!(dispute.process(&mut clients, &policy)?, 5.into());
+        tx(TxType::Resolve, 1).process(&mut clients, &policy)?;
+        let policy = Policy {
+            allow_partial_disputes: true,
+            ..Default::default()
+        };
+        assert_eq!(dispute.process(&mut clients, &policy)?, 2.into());
+        let dispute = tx(TxType::Dispute { amount: Some(3.into()) }, 1);
+        assert_eq!(dispute.process(&mut clients, &policy)?, 3.into());
+        assert_eq!(clients[&1].held, 5.into());
+        assert_eq!(clients[&1].available, 0.into());
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_amount_above_max() -> anyhow::Result<()> {
+        let mut clients = HashMap::new();
+        let policy = Policy {
+            max_amount: Some(10.into()),
+            ..Default::default()
+        };
+        let deposit = |amount| tx(TxType::Deposit { amount }, 1);
+        let below = Decimal::new(99999, 4);
+        assert_eq!(deposit(below).process(&mut clients, &policy)?, below);
+        let above = deposit(Decimal::new(100001, 4));
+        assert_eq!(
+            above.process(&mut clients, &policy).unwrap_err().to_string(),
+            "Amount exceeds max 10: 10.0001"
+        );
+        let withdrawal = tx(TxType::Withdrawal { amount: Decimal::new(101, 1) }, 2);
+        assert!(withdrawal.process(&mut clients, &policy).is_err());
+        assert_eq!(clients[&1].total, below);
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_tx_in_other_currency() -> anyhow::Result<()> {
+        let csv = "type,client,tx,amount,currency\n\
+                   deposit,1,1,3.0,\n\
+                   deposit,1,2,2.0,EUR\n\
+                   deposit,1,3,1.0,USD\n\
+                   withdrawal,1,4,1.0,usd\n\
+                   withdrawal,1,5,1.0,EUR\n";
+        let txs: Vec<Tx> = csv::Reader::from_reader(csv.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()?;
+        let mut clients = HashMap::new();
+        let policy = Policy::default();
+        txs[0].process(&mut clients, &policy)?;
+        assert_eq!(clients[&1].currency, None);
+        txs[1].process(&mut clients, &policy)?;
+        assert_eq!(clients[&1].currency.as_deref(), Some("EUR"));
+        assert_eq!(
+            txs[2].process(&mut clients, &policy).unwrap_err().to_string(),
+            "Account 1: Currency USD != EUR"
+        );
+        assert_eq!(
+            txs[3].process(&mut clients, &policy).unwrap_err().to_string(),
+            "Invalid currency usd"
+        );
+        txs[4].process(&mut clients, &policy)?;
+        assert_eq!(clients[&1].total, 4.into());
+        Ok(())
+    }
+
+    #[test]
+    fn should_dispute_by_amount_when_enabled() -> anyhow::Result<()> {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,3.0\n\
+                   deposit,1,2,2.0\n\
+                   dispute,1,99,3.0\n\
+                   dispute,1,2,\n";
+        let txs: Vec<Tx> = csv::Reader::from_reader(csv.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()?;
+        let policy = Policy {
+            dispute_by_amount: true,
+            ..Default::default()
+        };
+        let mut clients = HashMap::new();
+        for tx in &txs {
+            tx.process(&mut clients, &policy)?;
+        }
+        assert_eq!(clients[&1].disputed_tx_ids(), [1, 2]);
+        assert_eq!(clients[&1].held, 5.into());
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_header_missing_columns() -> anyhow::Result<()> {
+        ensure_columns(["amount", "tx", "type", "client", "note"])?;
+        ensure_columns([])?;
+        assert_eq!(
+            ensure_columns(["type", "client", "tx", "ammount"]).unwrap_err().to_string(),
+            "Missing columns amount in the header type,client,tx,ammount"
+        );
+        assert_eq!(
+            ensure_columns(["client", "tx"]).unwrap_err().to_string(),
+            "Missing columns type, amount in the header client,tx"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_missing_amount() -> anyhow::Result<()> {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 2,\n\
+                     withdrawal, 1, 4, \n\
+                     dispute, 1, 2,\n";
+        let mut rdr = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(input.as_bytes());
+        let errors: Vec<String> = rdr
+            .deserialize::<Tx>()
+            .filter_map(|result| result.err().map(|e| e.to_string()))
+            .collect();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].ends_with("Deposit row 2 missing amount"), "{}", errors[0]);
+        assert!(errors[1].ends_with("Withdrawal row 4 missing amount"), "{}", errors[1]);
+        let e = serde_json::from_str::<Tx>(r#"{"type":"deposit","client":1,"tx":7}"#).unwrap_err();
+        assert_eq!(e.to_string(), "Deposit row 7 missing amount");
+        let e = serde_json::from_str::<Tx>(r#"{"type":"delta","client":1,"tx":8}"#).unwrap_err();
+        assert_eq!(e.to_string(), "Delta row 8 missing available_delta");
+        Ok(())
+    }
+
+    #[test]
+    fn should_parse_fractions_only_when_allowed() -> anyhow::Result<()> {
+        let row = |json| serde_json::from_str::<TxRow>(json);
+        let fraction = r#"{"type":"deposit","client":1,"tx":1,"amount":"1/4"}"#;
+        let tx = row(fraction)?.into_tx(true)?;
+        assert!(matches!(tx.tx_type, TxType::Deposit { amount } if amount == Decimal::new(25, 2)));
+        let e = row(fraction)?.into_tx(false).unwrap_err();
+        assert_eq!(e.to_string(), "Fraction amounts are not allowed 1/4");
+        let tx = row(r#"{"type":"deposit","client":1,"tx":2,"amount":1.5}"#)?;
+        let tx = tx.into_tx(false)?;
+        assert!(matches!(tx.tx_type, TxType::Deposit { amount } if amount == Decimal::new(15, 1)));
+        Ok(())
+    }
+
+    #[test]
+    fn should_dump_schema_with_all_types() -> anyhow::Result<()> {
+        let mut dump = vec![];
+        dump_schema(&mut dump)?;
+        let schema: serde_json::Value = serde_json::from_slice(&dump)?;
+        assert_eq!(
+            schema["columns"],
+            serde_json::json!(["type", "client", "tx", "amount"])
+        );
+        let types = schema["types"].as_array().unwrap();
+        for (name, amount) in [
+            ("deposit", true),
+            ("withdrawal", true),
+            ("dispute", false),
+            ("resolve", false),
+            ("chargeback", false),
+        ] {
+            assert!(types
+                .iter()
+                .any(|t| t["type"] == name && t["amount"] == amount && t.get("requires").is_none()));
         }
+        Ok(())
     }
 }
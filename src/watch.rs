@@ -0,0 +1,77 @@
+use anyhow::ensure;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Events coming within this time after the first one are handled together,
+/// as a single write usually raises several of them.
+const DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// Calls `process` once and then again whenever any of the files changes, until it returns false.
+pub(crate) fn watch(
+    paths: &[PathBuf],
+    mut process: impl FnMut() -> anyhow::Result<bool>,
+) -> anyhow::Result<()> {
+    ensure!(!paths.is_empty(), "Nothing to watch, input files are missing");
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for path in paths {
+        watcher.watch(path, RecursiveMode::NonRecursive)?;
+    }
+    if !process()? {
+        return Ok(());
+    }
+    while let Ok(event) = rx.recv() {
+        if !matches!(event?.kind, EventKind::Modify(_) | EventKind::Create(_)) {
+            continue;
+        }
+        while rx.recv_timeout(DEBOUNCE).is_ok() {}
+        if !process()? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn should_process_again_on_change() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("tx_fun_watch_{}.csv", std::process::id()));
+        fs::write(&path, "type, client, tx, amount\n")?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (path, stop) = (path.clone(), stop.clone());
+            // keeps changing the file until the watcher stops
+            thread::spawn(move || {
+                for tx in 1.. {
+                    thread::sleep(Duration::from_millis(100));
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+                    writeln!(file, "deposit, 1, {}, 1.0", tx).unwrap();
+                }
+            })
+        };
+        let mut runs = 0;
+        let result = watch(std::slice::from_ref(&path), || {
+            runs += 1;
+            Ok(runs < 2)
+        });
+        stop.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+        fs::remove_file(&path)?;
+        result?;
+        assert_eq!(runs, 2);
+        Ok(())
+    }
+}